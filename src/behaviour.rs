@@ -1,62 +1,582 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
+use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
-use std::thread;
 use std::time::Duration;
 
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use futures_timer::Delay;
 use libp2p::core::{ConnectedPoint, Multiaddr, PeerId};
-use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters, SubstreamProtocol};
+use libp2p::swarm::{
+    IntoProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+    SubstreamProtocol,
+};
+use tracing::{debug, info, trace, warn};
 
+use crate::contents::{ReceiveSink, SendSource};
+use crate::error::TransferError;
 use crate::handler::OneShotHandler;
-use crate::protocol::{FileToSend, ProtocolEvent, TransferPayload};
+use crate::hash::HashAlgorithm;
+use crate::peer_info::PeerInfo;
+use crate::protocol::{
+    FileToSend, OfferAnswer, OfferRequest, ProtocolEvent, RunningTransfers, TransferPayload,
+};
+use crate::trust::TrustStore;
+use crate::types::{ContentHash, FileName, FilePath};
+
+type TransferHandler = OneShotHandler<TransferPayload, TransferPayload, ProtocolEvent>;
+
+/// Delay before the first redial, doubled after every further failure.
+const DIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Queued files for a peer are dropped after this many failed dials in a row.
+const MAX_DIAL_ATTEMPTS: u32 = 5;
+/// Default minimum time between two progress events of a transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// Upgrades time out each of their steps themselves, reporting it as `Failed`. This only
+/// bounds a whole transfer, which takes long for large files on slow links.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Builds the `OneShotHandler` once the remote peer is known, so that
+/// incoming offers can tell who they come from.
+pub struct TransferHandlerProto {
+    payload: TransferPayload,
+    /// How long an idle connection is kept.
+    timeout: Duration,
+    /// Only these peers may stay connected, `None` allows everyone.
+    allowed: Option<HashSet<PeerId>>,
+}
+
+impl IntoProtocolsHandler for TransferHandlerProto {
+    type Handler = TransferHandler;
+
+    fn into_handler(self, remote_peer_id: &PeerId, _: &ConnectedPoint) -> Self::Handler {
+        let payload = TransferPayload {
+            peer: Some(remote_peer_id.clone()),
+            ..self.payload
+        };
+        let proto = SubstreamProtocol::new(payload).with_timeout(UPGRADE_TIMEOUT);
+        let mut handler = TransferHandler::new(proto, self.timeout, UPGRADE_TIMEOUT);
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(remote_peer_id) {
+                handler.close();
+            }
+        }
+        handler
+    }
+
+    fn inbound_protocol(&self) -> TransferPayload {
+        self.payload.clone()
+    }
+}
+
+/// Failed dials of a peer and the timer until it may be dialed again.
+struct Backoff {
+    attempts: u32,
+    delay: Delay,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Outgoing,
+    Incoming,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferState {
+    /// Waiting for a connection to the peer.
+    Queued,
+    /// Waiting for `TransferBehaviour::accept` or `reject`.
+    Offered,
+    Running,
+}
+
+/// A transfer that has not finished yet, see `TransferBehaviour::transfers`.
+#[derive(Clone, Debug)]
+pub struct TransferStatus {
+    pub id: u64,
+    pub name: String,
+    /// Missing for queued files that go to whichever peer is connected first.
+    pub peer: Option<PeerId>,
+    pub direction: Direction,
+    pub state: TransferState,
+    /// Broadcast an outgoing transfer belongs to.
+    pub broadcast: Option<u64>,
+    pub bytes_done: u64,
+    /// 0 until the first `Progress` event of an outgoing transfer.
+    pub bytes_total: u64,
+}
+
+/// A file fanned out to several peers.
+struct Broadcast {
+    name: String,
+    total: usize,
+    pending: HashSet<PeerId>,
+    sent: Vec<PeerId>,
+    failed: Vec<PeerId>,
+}
 
 pub struct TransferBehaviour {
     pub peers: HashSet<PeerId>,
+    /// What peers announced about themselves, see `set_peer_info`.
+    peer_info: HashMap<PeerId, PeerInfo>,
     pub connected_peers: HashSet<PeerId>,
-    pub events: Vec<NetworkBehaviourAction<TransferPayload, TransferPayload>>,
+    pub events: Vec<NetworkBehaviourAction<TransferPayload, ProtocolEvent>>,
     payloads: Vec<FileToSend>,
+    target_path: String,
+    hash_algorithm: HashAlgorithm,
+    offers_sender: mpsc::UnboundedSender<OfferRequest>,
+    offers_receiver: mpsc::UnboundedReceiver<OfferRequest>,
+    pending_offers: HashMap<u64, oneshot::Sender<OfferAnswer>>,
+    /// Transfers that left the queue or were offered to us, until they finish.
+    transfers: HashMap<u64, TransferStatus>,
+    /// Ids of transfers in both directions, shared with the upgrades.
+    transfer_ids: Arc<AtomicU64>,
+    running: RunningTransfers,
+    progress_sender: mpsc::UnboundedSender<ProtocolEvent>,
+    progress_receiver: mpsc::UnboundedReceiver<ProtocolEvent>,
+    progress_interval: Duration,
+    broadcasts: HashMap<u64, Broadcast>,
+    next_broadcast_id: u64,
+    dialing: HashSet<PeerId>,
+    backoff: HashMap<PeerId, Backoff>,
+    trust_store: TrustStore,
+    refuse_untrusted: bool,
+    receive_sink: ReceiveSink,
+    dial_discovered: bool,
 }
 
 impl TransferBehaviour {
-    pub fn new() -> Self {
+    pub fn new(target_path: String) -> Self {
+        let (offers_sender, offers_receiver) = mpsc::unbounded();
+        let (progress_sender, progress_receiver) = mpsc::unbounded();
         TransferBehaviour {
             peers: HashSet::new(),
+            peer_info: HashMap::new(),
             connected_peers: HashSet::new(),
             events: vec![],
             payloads: vec![],
+            target_path,
+            hash_algorithm: HashAlgorithm::default(),
+            offers_sender,
+            offers_receiver,
+            pending_offers: HashMap::new(),
+            transfers: HashMap::new(),
+            transfer_ids: Arc::new(AtomicU64::new(0)),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            progress_sender,
+            progress_receiver,
+            progress_interval: PROGRESS_INTERVAL,
+            broadcasts: HashMap::new(),
+            next_broadcast_id: 0,
+            dialing: HashSet::new(),
+            backoff: HashMap::new(),
+            trust_store: TrustStore::default(),
+            refuse_untrusted: false,
+            receive_sink: ReceiveSink::default(),
+            dial_discovered: false,
+        }
+    }
+
+    /// Algorithm used to hash outgoing files, receivers verify with whatever the header declares.
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.hash_algorithm = algorithm;
+    }
+
+    /// Minimum time between two `ProtocolEvent::Progress` events of the same transfer.
+    pub fn set_progress_interval(&mut self, interval: Duration) {
+        self.progress_interval = interval;
+    }
+
+    /// Where accepted incoming files go, the download directory unless told otherwise.
+    pub fn set_receive_sink(&mut self, sink: ReceiveSink) {
+        self.receive_sink = sink;
+    }
+
+    /// Whether discovered peers are dialed right away, so they can introduce themselves.
+    /// Off by default: peers are only dialed once a file is queued for them, or by
+    /// `lookup_peer`.
+    pub fn set_dial_discovered(&mut self, dial: bool) {
+        self.dial_discovered = dial;
+    }
+
+    /// Records a peer found by discovery, see `set_dial_discovered`.
+    pub fn add_peer(&mut self, peer: PeerId) {
+        if self.dial_discovered {
+            self.dial_unidentified(&peer);
+        }
+        self.peers.insert(peer);
+    }
+
+    /// Dials the peer listening on `address`, for peers discovery does not find. It is
+    /// reported with `ProtocolEvent::Connected` once reached.
+    pub fn dial_address(&mut self, address: Multiaddr) {
+        self.events
+            .push(NetworkBehaviourAction::DialAddress { address });
+    }
+
+    /// Dials `peer` so it can tell its name, see `set_peer_info`, unless it already did,
+    /// is connected or being dialed. Peers whose last dial failed are left alone.
+    fn dial_unidentified(&mut self, peer: &PeerId) {
+        let known = self.peer_info.contains_key(peer)
+            || self.connected_peers.contains(peer)
+            || self.dialing.contains(peer)
+            || self.backoff.contains_key(peer);
+        if !known && !self.is_refused(peer) {
+            self.dialing.insert(peer.clone());
+            self.events.push(NetworkBehaviourAction::DialPeer {
+                peer_id: peer.clone(),
+            });
+        }
+    }
+
+    /// Stores the name and host name a peer announced, for example through libp2p identify.
+    pub fn set_peer_info(&mut self, peer: PeerId, info: PeerInfo) {
+        self.peer_info.insert(peer, info);
+    }
+
+    pub fn peer_info(&self, peer: &PeerId) -> Option<&PeerInfo> {
+        self.peer_info.get(peer)
+    }
+
+    /// Finds the peer meant by `name`: a `PeerId`, or the name or host name of a
+    /// discovered peer, or the name it has in the trust store.
+    pub fn resolve_peer(&self, name: &str) -> Result<PeerId, Box<dyn Error>> {
+        if let Ok(peer) = name.parse::<PeerId>() {
+            return Ok(peer);
+        }
+        let found: Vec<&PeerId> = self
+            .peers
+            .union(&self.connected_peers)
+            .filter(|peer| {
+                let announced = self
+                    .peer_info
+                    .get(*peer)
+                    .is_some_and(|info| info.matches(name));
+                let trusted = self
+                    .trust_store
+                    .name(peer)
+                    .is_some_and(|trusted| trusted.eq_ignore_ascii_case(name));
+                announced || trusted
+            })
+            .collect();
+        match found.as_slice() {
+            [peer] => Ok((*peer).clone()),
+            [] => Err(format!("No peer named {:?}", name).into()),
+            _ => Err(format!("{} peers are named {:?}, use a peer id", found.len(), name).into()),
+        }
+    }
+
+    /// Like `resolve_peer`, but when no peer has that name yet, the discovered peers that
+    /// did not tell their name are dialed, so a later lookup may find it.
+    pub fn lookup_peer(&mut self, name: &str) -> Result<PeerId, Box<dyn Error>> {
+        let result = self.resolve_peer(name);
+        if result.is_err() {
+            let peers: Vec<PeerId> = self.peers.iter().cloned().collect();
+            for peer in peers {
+                self.dial_unidentified(&peer);
+            }
         }
+        result
+    }
+
+    /// Offers from peers in `store` are accepted without an `IncomingOffer` event.
+    pub fn set_trust_store(&mut self, store: TrustStore) {
+        self.trust_store = store;
     }
 
-    pub fn push_payload(&mut self, filename: String) -> Result<(), Box<dyn Error>> {
+    pub fn trust_store(&self) -> &TrustStore {
+        &self.trust_store
+    }
+
+    pub fn trust_store_mut(&mut self) -> &mut TrustStore {
+        &mut self.trust_store
+    }
+
+    /// Closes connections from peers missing in the trust store instead of asking about
+    /// their offers.
+    pub fn set_refuse_untrusted(&mut self, refuse: bool) {
+        self.refuse_untrusted = refuse;
+    }
+
+    fn is_refused(&self, peer: &PeerId) -> bool {
+        self.refuse_untrusted && !self.trust_store.is_trusted(peer)
+    }
+
+    /// Lets the transfer announced by `ProtocolEvent::IncomingOffer` proceed.
+    pub fn accept(&mut self, id: u64) -> Result<(), Box<dyn Error>> {
+        self.answer(id, OfferAnswer::Accept)?;
+        if let Some(transfer) = self.transfers.get_mut(&id) {
+            transfer.state = TransferState::Running;
+        }
+        Ok(())
+    }
+
+    /// Declines the transfer announced by `ProtocolEvent::IncomingOffer`.
+    pub fn reject(&mut self, id: u64) -> Result<(), Box<dyn Error>> {
+        self.transfers.remove(&id);
+        self.answer(id, OfferAnswer::Reject)
+    }
+
+    fn answer(&mut self, id: u64, answer: OfferAnswer) -> Result<(), Box<dyn Error>> {
+        let sender = self
+            .pending_offers
+            .remove(&id)
+            .ok_or_else(|| format!("No pending offer {}", id))?;
+        sender
+            .send(answer)
+            .map_err(|_| format!("Offer {} has expired", id).into())
+    }
+
+    /// Queues a file or a whole directory for sending. Without a `peer` it goes to any
+    /// connected peer, otherwise the given peer is dialed first if needed. Returns the
+    /// transfer id used in `Progress` and `Cancelled` events and by `cancel`.
+    pub fn push_payload(
+        &mut self,
+        filename: String,
+        peer: Option<PeerId>,
+    ) -> Result<u64, Box<dyn Error>> {
+        self.check_target(&peer)?;
+        let file = self.file_to_send(filename, peer, None)?;
+        let id = file.transfer_id;
+        self.payloads.push(file);
+        Ok(id)
+    }
+
+    /// Queues contents to be sent under `name`, whatever the file they may come from is
+    /// called. Otherwise works like `push_payload`.
+    pub fn push_source(
+        &mut self,
+        name: String,
+        source: SendSource,
+        peer: Option<PeerId>,
+    ) -> Result<u64, Box<dyn Error>> {
+        self.check_target(&peer)?;
+        let file = match source {
+            SendSource::Path(path) => FileToSend {
+                name,
+                ..self.file_to_send(path, peer, None)?
+            },
+            source => FileToSend {
+                name,
+                source,
+                peer,
+                broadcast: None,
+                transfer_id: self.transfer_ids.fetch_add(1, Ordering::Relaxed),
+            },
+        };
+        let id = file.transfer_id;
+        self.payloads.push(file);
+        Ok(id)
+    }
+
+    fn check_target(&self, peer: &Option<PeerId>) -> Result<(), Box<dyn Error>> {
+        if let Some(peer) = peer {
+            if !self.peers.contains(peer) && !self.connected_peers.contains(peer) {
+                return Err(format!("Unknown peer {:?}", peer).into());
+            }
+            if self.is_refused(peer) {
+                return Err(format!("Peer {:?} is not trusted", peer).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Transfers in both directions that have not finished yet, oldest first.
+    pub fn transfers(&self) -> Vec<TransferStatus> {
+        let queued = self.payloads.iter().map(|file| TransferStatus {
+            id: file.transfer_id,
+            name: file.name.clone(),
+            peer: file.peer.clone(),
+            direction: Direction::Outgoing,
+            state: TransferState::Queued,
+            broadcast: file.broadcast,
+            bytes_done: 0,
+            bytes_total: 0,
+        });
+        let mut transfers: Vec<TransferStatus> =
+            self.transfers.values().cloned().chain(queued).collect();
+        transfers.sort_by_key(|transfer| transfer.id);
+        transfers
+    }
+
+    /// Stops a transfer in either direction, whether it is still queued, waiting for an
+    /// answer or already streaming. The sender tells the receiver, which removes the
+    /// partial file, so a cancelled transfer starts over when it is sent again. A transfer
+    /// that was interrupted instead keeps it and resumes.
+    pub fn cancel(&mut self, id: u64) -> Result<(), Box<dyn Error>> {
+        if let Some(index) = self.payloads.iter().position(|file| file.transfer_id == id) {
+            let file = self.payloads.remove(index);
+            if let (Some(broadcast), Some(peer)) = (file.broadcast, file.peer) {
+                self.finish_broadcast_peer(broadcast, peer, Err(TransferError::Cancelled));
+            }
+            let event = ProtocolEvent::Cancelled {
+                transfer_id: id,
+                name: file.name,
+                broadcast: file.broadcast,
+            };
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(event));
+            return Ok(());
+        }
+
+        // The upgrade reports `Cancelled` itself once it has stopped.
+        self.pending_offers.remove(&id);
+        let sender = self
+            .running
+            .lock()
+            .map_err(|_| "Transfers are unavailable")?
+            .remove(&id)
+            .ok_or_else(|| format!("No transfer {}", id))?;
+        sender
+            .send(())
+            .map_err(|_| format!("Transfer {} has already finished", id).into())
+    }
+
+    /// Queues a file for every discovered peer. Returns the broadcast id used in
+    /// `BroadcastProgress` and `BroadcastFinished` events.
+    pub fn push_broadcast(&mut self, filename: String) -> Result<u64, Box<dyn Error>> {
+        let peers: HashSet<PeerId> = self
+            .peers
+            .union(&self.connected_peers)
+            .filter(|peer| !self.is_refused(peer))
+            .cloned()
+            .collect();
+        if peers.is_empty() {
+            return Err("There are no peers to send to".into());
+        }
+        let id = self.next_broadcast_id;
+        self.next_broadcast_id += 1;
+
+        let mut name = String::new();
+        for peer in peers.iter() {
+            let file = self.file_to_send(filename.clone(), Some(peer.clone()), Some(id))?;
+            name = file.name.clone();
+            self.payloads.push(file);
+        }
+        let broadcast = Broadcast {
+            name,
+            total: peers.len(),
+            pending: peers,
+            sent: vec![],
+            failed: vec![],
+        };
+        self.broadcasts.insert(id, broadcast);
+        Ok(id)
+    }
+
+    fn file_to_send(
+        &self,
+        filename: String,
+        peer: Option<PeerId>,
+        broadcast: Option<u64>,
+    ) -> Result<FileToSend, Box<dyn Error>> {
         fs::metadata(&filename)?;
         let path = Path::new(&filename).canonicalize()?;
         let name = path
             .file_name()
-            .expect("There is no file name")
-            .to_str()
-            .expect("Expected a name")
+            .and_then(|name| name.to_str())
+            .ok_or("There is no file name")?
             .to_string();
-        let path_string = path.to_str().expect("Expected a path name").to_string();
-        let file = FileToSend {
+        let path_string = path.to_str().ok_or("Expected a path name")?.to_string();
+        Ok(FileToSend {
             name,
-            path: path_string,
+            source: SendSource::Path(path_string),
+            peer,
+            broadcast,
+            transfer_id: self.transfer_ids.fetch_add(1, Ordering::Relaxed),
+        })
+    }
+
+    /// Records the outcome of one peer of a broadcast and reports it.
+    fn finish_broadcast_peer(&mut self, id: u64, peer: PeerId, result: Result<(), TransferError>) {
+        let broadcast = match self.broadcasts.get_mut(&id) {
+            Some(broadcast) => broadcast,
+            None => return,
         };
-        self.payloads.push(file);
-        Ok(())
+        if !broadcast.pending.remove(&peer) {
+            return;
+        }
+        match result {
+            Ok(_) => broadcast.sent.push(peer.clone()),
+            Err(_) => broadcast.failed.push(peer.clone()),
+        }
+        let event = ProtocolEvent::BroadcastProgress {
+            id,
+            peer,
+            result,
+            done: broadcast.total - broadcast.pending.len(),
+            total: broadcast.total,
+        };
+        self.events
+            .push(NetworkBehaviourAction::GenerateEvent(event));
+
+        if broadcast.pending.is_empty() {
+            if let Some(broadcast) = self.broadcasts.remove(&id) {
+                let event = ProtocolEvent::BroadcastFinished {
+                    id,
+                    name: broadcast.name,
+                    sent: broadcast.sent,
+                    failed: broadcast.failed,
+                };
+                self.events
+                    .push(NetworkBehaviourAction::GenerateEvent(event));
+            }
+        }
+    }
+
+    /// Fails every broadcast still waiting on a peer we can no longer reach.
+    fn fail_broadcasts_to(&mut self, peer: &PeerId, error: TransferError) {
+        self.payloads
+            .retain(|file| file.broadcast.is_none() || file.peer.as_ref() != Some(peer));
+        let ids: Vec<u64> = self
+            .broadcasts
+            .iter()
+            .filter(|(_, broadcast)| broadcast.pending.contains(peer))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            self.finish_broadcast_peer(id, peer.clone(), Err(error.clone()));
+        }
     }
 }
 
 impl NetworkBehaviour for TransferBehaviour {
-    type ProtocolsHandler = OneShotHandler<TransferPayload, TransferPayload, ProtocolEvent>;
-    type OutEvent = TransferPayload;
+    type ProtocolsHandler = TransferHandlerProto;
+    type OutEvent = ProtocolEvent;
 
     fn new_handler(&mut self) -> Self::ProtocolsHandler {
         let timeout = Duration::from_secs(120);
-        let tp = TransferPayload::default();
-        let proto = SubstreamProtocol::new(tp).with_timeout(timeout);
-        Self::ProtocolsHandler::new(proto, timeout, timeout)
+        let payload = TransferPayload {
+            target_path: self.target_path.clone(),
+            offers: Some(self.offers_sender.clone()),
+            transfer_ids: Some(self.transfer_ids.clone()),
+            progress: Some(self.progress_sender.clone()),
+            progress_interval: self.progress_interval,
+            running: Some(self.running.clone()),
+            sink: self.receive_sink.clone(),
+            ..TransferPayload::default()
+        };
+        let allowed = if self.refuse_untrusted {
+            Some(
+                self.trust_store
+                    .peers()
+                    .into_iter()
+                    .map(|(peer, _)| peer.clone())
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        TransferHandlerProto {
+            payload,
+            timeout,
+            allowed,
+        }
     }
 
     fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
@@ -64,96 +584,667 @@ impl NetworkBehaviour for TransferBehaviour {
     }
 
     fn inject_connected(&mut self, peer: PeerId, point: ConnectedPoint) {
-        println!("Connected to peer: {:?} {:?}", peer, point);
-        match point {
-            ConnectedPoint::Dialer { address: _ } => {
-                println!("I'm a dialer now.");
-            }
-            ConnectedPoint::Listener {
-                local_addr: _,
-                send_back_addr: _,
-            } => println!("I am listener now"),
-        };
-        self.connected_peers.insert(peer);
+        debug!("Connected to {} {:?}", peer.to_base58(), point);
+        self.dialing.remove(&peer);
+        self.backoff.remove(&peer);
+        if self.is_refused(&peer) {
+            // Its handler closes the connection.
+            info!("Refusing untrusted peer {}", peer.to_base58());
+            self.peers.remove(&peer);
+            return;
+        }
+        self.connected_peers.insert(peer.clone());
+        self.events.push(NetworkBehaviourAction::GenerateEvent(
+            ProtocolEvent::Connected { peer },
+        ));
     }
 
     fn inject_dial_failure(&mut self, peer: &PeerId) {
-        println!("Dial failure {:?}", peer);
+        debug!("Dial failure {}", peer.to_base58());
         self.connected_peers.remove(peer);
+        self.dialing.remove(peer);
+
+        let attempts = self.backoff.get(peer).map_or(0, |b| b.attempts) + 1;
+        if attempts >= MAX_DIAL_ATTEMPTS {
+            warn!("Giving up on {} after {} dials", peer.to_base58(), attempts);
+            self.backoff.remove(peer);
+            // Until discovery finds it again, files for any peer go elsewhere.
+            self.peers.remove(peer);
+            let error: TransferError =
+                io::Error::new(io::ErrorKind::NotConnected, "Dial failure").into();
+            let (dropped, kept): (Vec<FileToSend>, Vec<FileToSend>) = self
+                .payloads
+                .drain(..)
+                .partition(|file| file.broadcast.is_none() && file.peer.as_ref() == Some(peer));
+            self.payloads = kept;
+            for file in dropped {
+                self.events.push(NetworkBehaviourAction::GenerateEvent(
+                    ProtocolEvent::Failed {
+                        transfer_id: file.transfer_id,
+                        name: Some(file.name),
+                        broadcast: None,
+                        error: error.clone(),
+                    },
+                ));
+            }
+            self.fail_broadcasts_to(peer, error);
+        } else {
+            let delay = Delay::new(DIAL_BACKOFF * 2u32.pow(attempts - 1));
+            self.backoff
+                .insert(peer.clone(), Backoff { attempts, delay });
+        }
     }
 
     fn inject_disconnected(&mut self, peer: &PeerId, _: ConnectedPoint) {
-        println!("Disconnected: {:?}", peer);
-        self.connected_peers.remove(peer);
+        debug!("Disconnected from {}", peer.to_base58());
+        if self.connected_peers.remove(peer) {
+            self.events.push(NetworkBehaviourAction::GenerateEvent(
+                ProtocolEvent::Disconnected { peer: peer.clone() },
+            ));
+        }
+        self.dialing.remove(peer);
         self.peers.remove(peer);
+        let error: TransferError =
+            io::Error::new(io::ErrorKind::ConnectionAborted, "Disconnected").into();
+
+        // The upgrades of the connection are gone without a word, their transfers fail.
+        let ids: Vec<u64> = self
+            .transfers
+            .values()
+            .filter(|transfer| transfer.peer.as_ref() == Some(peer))
+            .map(|transfer| transfer.id)
+            .collect();
+        for id in ids {
+            let transfer = match self.transfers.remove(&id) {
+                Some(transfer) => transfer,
+                None => continue,
+            };
+            self.pending_offers.remove(&id);
+            // Broadcasts report their peers below.
+            if transfer.broadcast.is_some() {
+                continue;
+            }
+            let event = ProtocolEvent::Failed {
+                transfer_id: id,
+                name: Some(transfer.name),
+                broadcast: None,
+                error: error.clone(),
+            };
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(event));
+        }
+        self.fail_broadcasts_to(peer, error);
     }
 
-    fn inject_node_event(&mut self, _peer: PeerId, event: ProtocolEvent) {
+    fn inject_node_event(&mut self, peer: PeerId, event: ProtocolEvent) {
+        if let Some(id) = event.finished_transfer() {
+            self.transfers.remove(&id);
+        }
         match event {
-            ProtocolEvent::Received {
-                name,
-                hash,
-                path,
-                size_bytes,
+            ProtocolEvent::Failed {
+                broadcast: Some(id),
+                error,
+                ..
+            } => self.finish_broadcast_peer(id, peer, Err(error)),
+            ProtocolEvent::Sent {
+                broadcast: Some(id),
+                ..
+            } => self.finish_broadcast_peer(id, peer, Ok(())),
+            ProtocolEvent::Cancelled {
+                broadcast: Some(id),
+                ..
             } => {
-                let event = TransferPayload::new(name, hash, path, size_bytes);
+                self.finish_broadcast_peer(id, peer, Err(TransferError::Cancelled));
+                self.events
+                    .push(NetworkBehaviourAction::GenerateEvent(event));
+            }
+            event => {
                 self.events
                     .push(NetworkBehaviourAction::GenerateEvent(event));
             }
-            ProtocolEvent::Sent => println!("Node Sent event"),
         };
     }
 
     fn poll(
         &mut self,
-        _: &mut Context,
+        cx: &mut Context,
         _: &mut impl PollParameters,
-    ) -> Poll<NetworkBehaviourAction<TransferPayload, TransferPayload>> {
-        match self.events.pop() {
-            Some(e) => {
-                println!("Got event from the queue: {:?}", e);
-                return Poll::Ready(e);
-            }
-            None => {}
-        };
-
-        if self.connected_peers.len() > 0 {
-            let peer = self.connected_peers.iter().nth(0).unwrap();
-            match self.payloads.pop() {
-                Some(value) => {
-                    let event = TransferPayload::new(value.name, value.path, "".to_string(), 0);
-                    return Poll::Ready(NetworkBehaviourAction::SendEvent {
-                        peer_id: peer.to_owned(),
-                        event,
-                    });
+    ) -> Poll<NetworkBehaviourAction<TransferPayload, ProtocolEvent>> {
+        // Offers whose upgrade timed out can no longer be answered.
+        let expired: Vec<u64> = self
+            .pending_offers
+            .iter()
+            .filter(|(_, sender)| sender.is_canceled())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.pending_offers.remove(&id);
+            self.transfers.remove(&id);
+        }
+
+        while let Poll::Ready(Some(offer)) = self.offers_receiver.poll_next_unpin(cx) {
+            let id = offer.id;
+            let mut transfer = TransferStatus {
+                id,
+                name: offer.header.name.clone(),
+                peer: Some(offer.peer.clone()),
+                direction: Direction::Incoming,
+                state: TransferState::Offered,
+                broadcast: None,
+                bytes_done: 0,
+                bytes_total: offer.header.known_size().unwrap_or(0),
+            };
+            if let Some(name) = self.trust_store.name(&offer.peer) {
+                info!("Accepting {:?} from {}", offer.header.name, name);
+                let _ = offer.answer.send(OfferAnswer::Accept);
+                transfer.state = TransferState::Running;
+                self.transfers.insert(id, transfer);
+                continue;
+            }
+            if self.is_refused(&offer.peer) {
+                info!(
+                    "Declining {:?} from untrusted {}",
+                    offer.header.name,
+                    offer.peer.to_base58()
+                );
+                let _ = offer.answer.send(OfferAnswer::Reject);
+                continue;
+            }
+            self.pending_offers.insert(id, offer.answer);
+            self.transfers.insert(id, transfer);
+            let size_bytes = offer.header.known_size().unwrap_or(0) as usize;
+            let event = ProtocolEvent::IncomingOffer {
+                id,
+                peer: offer.peer,
+                name: offer.header.name,
+                hash: offer.header.hash,
+                size_bytes,
+            };
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(event));
+        }
+
+        while let Poll::Ready(Some(event)) = self.progress_receiver.poll_next_unpin(cx) {
+            if let ProtocolEvent::Progress {
+                transfer_id,
+                bytes_done,
+                bytes_total,
+                ..
+            } = &event
+            {
+                if let Some(transfer) = self.transfers.get_mut(transfer_id) {
+                    transfer.bytes_done = *bytes_done;
+                    transfer.bytes_total = *bytes_total;
                 }
-                None => return Poll::Pending,
             }
-        } else {
-            for peer in self.peers.iter() {
-                if !self.connected_peers.contains(peer) {
-                    println!("Will try to dial: {:?}", peer);
-                    let millis = Duration::from_millis(100);
-                    thread::sleep(millis);
-                    return Poll::Ready(NetworkBehaviourAction::DialPeer {
-                        peer_id: peer.to_owned(),
-                    });
-                } else {
-                    match self.payloads.pop() {
-                        Some(value) => {
-                            let event =
-                                TransferPayload::new(value.name, value.path, "".to_string(), 0);
-                            return Poll::Ready(NetworkBehaviourAction::SendEvent {
-                                peer_id: peer.to_owned(),
-                                event,
-                            });
-                        }
-                        None => return Poll::Pending,
-                    }
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(event));
+        }
+
+        if !self.events.is_empty() {
+            let e = self.events.remove(0);
+            trace!("Got event from the queue: {:?}", e);
+            return Poll::Ready(e);
+        }
+
+        let mut to_dial = vec![];
+        for index in 0..self.payloads.len() {
+            let target = match &self.payloads[index].peer {
+                Some(peer) => peer.clone(),
+                None => match self
+                    .connected_peers
+                    .iter()
+                    .next()
+                    .or_else(|| self.peers.iter().find(|peer| !self.is_refused(peer)))
+                {
+                    Some(peer) => peer.clone(),
+                    None => continue,
+                },
+            };
+
+            if self.connected_peers.contains(&target) {
+                let value = self.payloads.remove(index);
+                let transfer = TransferStatus {
+                    id: value.transfer_id,
+                    name: value.name.clone(),
+                    peer: Some(target.clone()),
+                    direction: Direction::Outgoing,
+                    state: TransferState::Running,
+                    broadcast: value.broadcast,
+                    bytes_done: 0,
+                    bytes_total: 0,
+                };
+                self.transfers.insert(value.transfer_id, transfer);
+                let path = match &value.source {
+                    SendSource::Path(path) => path.clone(),
+                    _ => String::new(),
+                };
+                let event = TransferPayload {
+                    hash_algorithm: self.hash_algorithm.to_string(),
+                    source: Some(value.source),
+                    broadcast: value.broadcast,
+                    transfer_id: value.transfer_id,
+                    peer: Some(target.clone()),
+                    progress: Some(self.progress_sender.clone()),
+                    progress_interval: self.progress_interval,
+                    running: Some(self.running.clone()),
+                    ..TransferPayload::new(
+                        FileName::from(value.name),
+                        FilePath::from(path),
+                        ContentHash::default(),
+                        0,
+                    )
+                };
+                return Poll::Ready(NetworkBehaviourAction::SendEvent {
+                    peer_id: target,
+                    event,
+                });
+            } else if self.peers.contains(&target) && !to_dial.contains(&target) {
+                to_dial.push(target);
+            }
+        }
+
+        for peer in to_dial {
+            if self.dialing.contains(&peer) {
+                continue;
+            }
+            if let Some(backoff) = self.backoff.get_mut(&peer) {
+                if backoff.delay.poll_unpin(cx).is_pending() {
+                    continue;
                 }
             }
+            debug!("Will try to dial {}", peer.to_base58());
+            self.dialing.insert(peer.clone());
+            self.events
+                .push(NetworkBehaviourAction::DialPeer { peer_id: peer });
+        }
+
+        if !self.events.is_empty() {
+            return Poll::Ready(self.events.remove(0));
         }
 
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::TransferHeader;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+    use futures::task::noop_waker_ref;
+    use libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+    use libp2p::swarm::{ProtocolsHandler, ProtocolsHandlerEvent};
+    use std::{env, iter, process};
+
+    struct Params(PeerId);
+
+    impl PollParameters for Params {
+        type SupportedProtocolsIter = iter::Empty<Vec<u8>>;
+        type ListenedAddressesIter = iter::Empty<Multiaddr>;
+        type ExternalAddressesIter = iter::Empty<Multiaddr>;
+
+        fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+            iter::empty()
+        }
+
+        fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+            iter::empty()
+        }
+
+        fn external_addresses(&self) -> Self::ExternalAddressesIter {
+            iter::empty()
+        }
+
+        fn local_peer_id(&self) -> &PeerId {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn test_resolve_peer() {
+        let mut behaviour = TransferBehaviour::new("/tmp/files".to_string());
+        let (alice, bob, stranger) = (PeerId::random(), PeerId::random(), PeerId::random());
+        behaviour.add_peer(alice.clone());
+        behaviour.add_peer(bob.clone());
+        let info = |name: &str| PeerInfo {
+            name: name.to_string(),
+            hostname: "laptop".to_string(),
+        };
+        behaviour.set_peer_info(alice.clone(), info("alice"));
+        behaviour.set_peer_info(bob.clone(), info("bob"));
+        let trust_store = behaviour.trust_store_mut();
+        trust_store.add(bob.clone(), "Robert".to_string()).unwrap();
+        trust_store
+            .add(stranger.clone(), "Carol".to_string())
+            .unwrap();
+
+        assert_eq!(behaviour.resolve_peer("Alice").unwrap(), alice);
+        assert_eq!(behaviour.resolve_peer("robert").unwrap(), bob);
+        assert_eq!(behaviour.resolve_peer(&bob.to_base58()).unwrap(), bob);
+        // Trusted, but not found on the network.
+        assert!(behaviour.resolve_peer("Carol").is_err());
+        let error = behaviour.resolve_peer("laptop").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "2 peers are named \"laptop\", use a peer id"
+        );
+    }
+
+    #[test]
+    fn test_discovered_peers_are_dialed_on_demand() {
+        let dials = |behaviour: &mut TransferBehaviour| {
+            let dials = behaviour
+                .events
+                .iter()
+                .filter(|event| matches!(event, NetworkBehaviourAction::DialPeer { .. }))
+                .count();
+            behaviour.events.clear();
+            dials
+        };
+        let mut behaviour = TransferBehaviour::new("/tmp/files".to_string());
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        behaviour.add_peer(alice.clone());
+        behaviour.add_peer(bob.clone());
+        assert_eq!(dials(&mut behaviour), 0);
+
+        behaviour.set_peer_info(
+            alice.clone(),
+            PeerInfo {
+                name: "alice".to_string(),
+                hostname: "laptop".to_string(),
+            },
+        );
+        assert_eq!(behaviour.lookup_peer("alice").unwrap(), alice);
+        assert_eq!(dials(&mut behaviour), 0);
+        // Only bob may still be called "carol".
+        assert!(behaviour.lookup_peer("carol").is_err());
+        assert_eq!(dials(&mut behaviour), 1);
+        assert!(behaviour.lookup_peer("carol").is_err());
+        assert_eq!(dials(&mut behaviour), 0);
+
+        let mut behaviour = TransferBehaviour::new("/tmp/files".to_string());
+        behaviour.set_dial_discovered(true);
+        behaviour.add_peer(alice);
+        assert_eq!(dials(&mut behaviour), 1);
+    }
+
+    #[test]
+    fn test_pushed_sources_keep_their_name() {
+        let mut behaviour = TransferBehaviour::new("/tmp/files".to_string());
+        let path = SendSource::Path("Cargo.toml".to_string());
+        let id = behaviour
+            .push_source("manifest.toml".to_string(), path, None)
+            .unwrap();
+        let queued = &behaviour.payloads[0];
+        assert_eq!(queued.transfer_id, id);
+        assert_eq!(queued.name, "manifest.toml");
+        assert!(matches!(&queued.source, SendSource::Path(path) if path.ends_with("Cargo.toml")));
+    }
+
+    #[test]
+    fn test_dial_failures_fail_queued_files() {
+        let mut behaviour = TransferBehaviour::new("/tmp/files".to_string());
+        let peer = PeerId::random();
+        behaviour.add_peer(peer.clone());
+        let source = || SendSource::bytes(b"contents".to_vec());
+        let targeted = behaviour
+            .push_source("a.txt".to_string(), source(), Some(peer.clone()))
+            .unwrap();
+        let untargeted = behaviour
+            .push_source("b.txt".to_string(), source(), None)
+            .unwrap();
+        behaviour.events.clear();
+
+        for _ in 1..MAX_DIAL_ATTEMPTS {
+            behaviour.inject_dial_failure(&peer);
+        }
+        assert!(behaviour.events.is_empty());
+        assert!(behaviour.peers.contains(&peer));
+
+        behaviour.inject_dial_failure(&peer);
+        assert!(!behaviour.peers.contains(&peer));
+        let failed: Vec<u64> = behaviour
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::GenerateEvent(ProtocolEvent::Failed {
+                    transfer_id,
+                    ..
+                }) => Some(*transfer_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(failed, vec![targeted]);
+        // Files for any peer wait for another one.
+        let queued: Vec<u64> = behaviour
+            .payloads
+            .iter()
+            .map(|file| file.transfer_id)
+            .collect();
+        assert_eq!(queued, vec![untargeted]);
+    }
+
+    #[test]
+    fn test_disconnects_fail_running_transfers() {
+        let mut behaviour = TransferBehaviour::new("/tmp/files".to_string());
+        let mut params = Params(PeerId::random());
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let peer = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        };
+        behaviour.inject_connected(peer.clone(), endpoint.clone());
+        let outgoing = behaviour
+            .push_source(
+                "a.txt".to_string(),
+                SendSource::bytes(b"contents".to_vec()),
+                Some(peer.clone()),
+            )
+            .unwrap();
+        let (answer, _answers) = oneshot::channel();
+        let offer = OfferRequest {
+            id: 100,
+            peer: peer.clone(),
+            header: TransferHeader {
+                name: "b.txt".to_string(),
+                ..TransferHeader::default()
+            },
+            answer,
+        };
+        behaviour.offers_sender.unbounded_send(offer).unwrap();
+        while behaviour.poll(&mut cx, &mut params).is_ready() {}
+        assert_eq!(behaviour.transfers().len(), 2);
+
+        behaviour.inject_disconnected(&peer, endpoint);
+        let mut failed: Vec<u64> = behaviour
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                NetworkBehaviourAction::GenerateEvent(ProtocolEvent::Failed {
+                    transfer_id,
+                    ..
+                }) => Some(*transfer_id),
+                _ => None,
+            })
+            .collect();
+        failed.sort();
+        assert_eq!(failed, vec![outgoing, 100]);
+        assert!(behaviour.transfers().is_empty());
+        assert!(behaviour.pending_offers.is_empty());
+    }
+
+    #[test]
+    fn test_received_file_round_trip() {
+        let dir = env::temp_dir().join(format!("p2pshare-round-trip-{}", process::id()));
+        let target_path = dir.join("received").to_string_lossy().to_string();
+        let source = dir.join("notes.txt");
+        let contents = b"contents sent through the handler and the behaviour".to_vec();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&source, &contents).unwrap();
+
+        let mut behaviour = TransferBehaviour::new(target_path.clone());
+        let mut params = Params(PeerId::random());
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let peer = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        };
+        behaviour.inject_connected(peer.clone(), endpoint.clone());
+        match behaviour.poll(&mut cx, &mut params) {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(ProtocolEvent::Connected {
+                peer: connected,
+            })) => assert_eq!(connected, peer),
+            _ => panic!("Expected the connection to be reported"),
+        }
+        behaviour
+            .push_payload(source.to_string_lossy().to_string(), Some(peer.clone()))
+            .unwrap();
+
+        let outgoing = match behaviour.poll(&mut cx, &mut params) {
+            Poll::Ready(NetworkBehaviourAction::SendEvent { event, .. }) => event,
+            _ => panic!("Expected the payload to be sent"),
+        };
+        let mut handler = behaviour.new_handler().into_handler(&peer, &endpoint);
+        handler.inject_event(outgoing);
+        let outbound = match handler.poll(&mut cx) {
+            Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol, .. }) => {
+                protocol.into_upgrade().1
+            }
+            _ => panic!("Expected an outbound substream request"),
+        };
+        let inbound = handler.listen_protocol().into_upgrade().1;
+        let info = inbound.protocol_info().next().unwrap();
+
+        let (sent, received, offer) = task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+
+            let answer = async {
+                let offer = future::poll_fn(|cx| loop {
+                    match behaviour.poll(cx, &mut params) {
+                        Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+                            ProtocolEvent::IncomingOffer { id, .. },
+                        )) => return Poll::Ready(id),
+                        Poll::Ready(_) => continue,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                })
+                .await;
+                behaviour.accept(offer).unwrap();
+                offer
+            };
+            let (sent, received, offer) = future::join3(
+                outbound.upgrade_outbound(client, info),
+                inbound.upgrade_inbound(server, info),
+                answer,
+            )
+            .await;
+            (sent.unwrap(), received.unwrap(), offer)
+        });
+        assert!(matches!(sent, ProtocolEvent::Sent { .. }));
+
+        handler.inject_fully_negotiated_inbound(received);
+        let event = match handler.poll(&mut cx) {
+            Poll::Ready(ProtocolsHandlerEvent::Custom(event)) => event,
+            _ => panic!("Expected the handler to report the transfer"),
+        };
+        behaviour.inject_node_event(peer.clone(), event);
+
+        let mut hasher = HashAlgorithm::default().hasher();
+        hasher.update(&contents);
+        match behaviour.poll(&mut cx, &mut params) {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(ProtocolEvent::Received {
+                transfer_id,
+                name,
+                path,
+                hash,
+                hash_algorithm,
+                size_bytes,
+                verified,
+                contents: received,
+            })) => {
+                assert_eq!(transfer_id, offer);
+                assert_eq!(name, FileName::from("notes.txt"));
+                assert!(path.as_str().starts_with(&target_path));
+                assert_eq!(fs::read(&path).unwrap(), contents);
+                assert_eq!(hash, ContentHash::from(hasher.finish()));
+                assert_eq!(hash_algorithm, HashAlgorithm::default().to_string());
+                assert_eq!(size_bytes, contents.len());
+                assert!(verified);
+                assert!(received.is_none());
+            }
+            _ => panic!("Expected a Received event"),
+        }
+        behaviour.inject_node_event(peer, sent);
+        assert!(behaviour.transfers().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sources_received_to_memory() {
+        let contents = b"contents generated on the fly".to_vec();
+        let transfer = |source: SendSource| {
+            let outbound = TransferPayload {
+                hash_algorithm: HashAlgorithm::default().to_string(),
+                source: Some(source),
+                ..TransferPayload::new(
+                    FileName::from("generated.txt"),
+                    FilePath::default(),
+                    ContentHash::default(),
+                    0,
+                )
+            };
+            let inbound = TransferPayload {
+                sink: ReceiveSink::memory(1 << 20),
+                ..TransferPayload::default()
+            };
+            let info = inbound.protocol_info().next().unwrap();
+            task::block_on(async {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let client = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let (server, _) = listener.accept().await.unwrap();
+                let (sent, received) = future::join(
+                    outbound.upgrade_outbound(client, info),
+                    inbound.upgrade_inbound(server, info),
+                )
+                .await;
+                assert!(matches!(sent.unwrap(), ProtocolEvent::Sent { .. }));
+                received.unwrap()
+            })
+        };
+
+        // Every source is hashed while it is sent, whether its size is known or not.
+        let sources = vec![
+            SendSource::bytes(contents.clone()),
+            SendSource::reader(&b"contents generated on the fly"[..], None),
+            SendSource::reader(&b"contents generated on the fly"[..], Some(29)),
+        ];
+        for source in sources {
+            match transfer(source) {
+                ProtocolEvent::Received {
+                    name,
+                    path,
+                    size_bytes,
+                    verified,
+                    contents: received,
+                    ..
+                } => {
+                    assert_eq!(name, FileName::from("generated.txt"));
+                    assert!(path.as_str().is_empty());
+                    assert_eq!(size_bytes, contents.len());
+                    assert!(verified);
+                    assert_eq!(received.as_deref(), Some(&contents));
+                }
+                event => panic!("Expected a Received event, got {:?}", event),
+            }
+        }
+    }
+}
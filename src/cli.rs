@@ -0,0 +1,348 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use p2pshare::hash::HashAlgorithm;
+use p2pshare::{keypair, trust};
+
+use crate::control;
+
+pub const USAGE: &str = "\
+Usage: p2pshare [options] [command]
+
+Commands:
+  listen, daemon              Receive files and read commands from stdin (default)
+  send <path> [--to <peer>]   Send a file or directory and exit once it is done
+  peers                       List the peers found on the network
+  status                      Show the identity and the configuration of this node
+  identity [--rotate]         Print the peer id, or replace the keypair first
+  trust add <peer> <name>     Accept files from a peer without asking
+  trust remove <peer>
+  trust list
+  help
+
+Options:
+  -l, --listen <address>      Multiaddr to listen on [default: /ip4/0.0.0.0/tcp/0]
+  -d, --download-dir <dir>    Where received files are saved [default: /tmp/files]
+  --identity <path>           Keypair file [default: ~/.config/p2pshare/identity]
+  --trusted <path>            Trusted peers file [default: ~/.config/p2pshare/trusted]
+  --trusted-only              Refuse connections from peers that are not trusted
+  --name <name>               Name announced to other peers [default: $USER]
+  --hash <algorithm>          blake3 or sha256 [default: blake3]
+  --progress-interval <ms>    Time between progress updates
+  --wait <seconds>            How long `send` and `peers` look for peers [default: 5]
+  --json                      Print events as one JSON object per line
+  --control <path>            Socket other programs drive a listening node through
+                              [default: ~/.config/p2pshare/control.sock]
+  --no-control                Do not create the control socket
+  -v, --verbose               Log more, repeat for even more
+  -q, --quiet                 Log less, repeat for errors only
+  --log-level <filter>        Log filter like \"info,p2pshare::protocol=debug\",
+                              also read from $P2PSHARE_LOG [default: warn,p2pshare=info]
+
+Interactive commands:
+  send <path> [--to <peer>], broadcast <path>, peers, list, status, accept <id>,
+  reject <id>, cancel <id>, trust <peer> <name>, untrust <peer>, trusted, help, quit";
+
+const DEFAULT_TARGET_PATH: &str = "/tmp/files";
+const DEFAULT_LISTEN_ADDRESS: &str = "/ip4/0.0.0.0/tcp/0";
+const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Listen,
+    Send { path: String, to: Option<String> },
+    Peers,
+    Status,
+    Identity { rotate: bool },
+    Trust(TrustCommand),
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TrustCommand {
+    Add { peer: String, name: String },
+    Remove { peer: String },
+    List,
+}
+
+pub struct Options {
+    pub command: Command,
+    pub listen_address: String,
+    pub target_path: String,
+    /// `None` when neither `--identity` nor a config directory is there, see
+    /// `Options::identity_path`.
+    pub identity_path: Option<PathBuf>,
+    pub trust_path: Option<PathBuf>,
+    pub trusted_only: bool,
+    pub name: Option<String>,
+    pub hash_algorithm: HashAlgorithm,
+    pub progress_interval: Option<Duration>,
+    /// How long one-off commands wait for peers to show up.
+    pub wait: Duration,
+    /// Whether events are printed as JSON lines instead of text.
+    pub json: bool,
+    /// Number of `-v` minus the number of `-q`.
+    pub verbosity: i8,
+    pub log_filter: Option<String>,
+    /// Where `listen` accepts JSON-RPC calls, if anywhere.
+    pub control_path: Option<PathBuf>,
+}
+
+impl Options {
+    /// The keypair file, an error when there is no config directory to put it in.
+    pub fn identity_path(&self) -> Result<&Path, Box<dyn Error>> {
+        config_file(&self.identity_path, "--identity")
+    }
+
+    /// The trusted peers file, an error when there is no config directory to put it in.
+    pub fn trust_path(&self) -> Result<&Path, Box<dyn Error>> {
+        config_file(&self.trust_path, "--trusted")
+    }
+}
+
+fn config_file<'a>(path: &'a Option<PathBuf>, option: &str) -> Result<&'a Path, Box<dyn Error>> {
+    path.as_deref().ok_or_else(|| {
+        format!(
+            "Cannot find the config directory, set HOME or XDG_CONFIG_HOME, or pass {}",
+            option
+        )
+        .into()
+    })
+}
+
+fn number(option: &str, value: String) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value {:?} for {}: {}", value, option, e))
+}
+
+/// Reads the options and the command from `args`, without the program name. Options
+/// may come before or after the command.
+pub fn parse_options(args: impl Iterator<Item = String>) -> Result<Options, Box<dyn Error>> {
+    let mut args = args;
+    let mut options = Options {
+        command: Command::Listen,
+        listen_address: DEFAULT_LISTEN_ADDRESS.to_string(),
+        target_path: DEFAULT_TARGET_PATH.to_string(),
+        identity_path: keypair::default_path(),
+        trust_path: trust::default_path(),
+        trusted_only: false,
+        name: None,
+        hash_algorithm: HashAlgorithm::default(),
+        progress_interval: None,
+        wait: DEFAULT_WAIT,
+        json: false,
+        verbosity: 0,
+        log_filter: None,
+        control_path: control::default_path(),
+    };
+    let mut words = vec![];
+    let mut to = None;
+    let mut rotate = false;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("Missing value for {}", name));
+        match arg.as_str() {
+            "-l" | "--listen" => options.listen_address = value("--listen")?,
+            "-d" | "--download-dir" => options.target_path = value("--download-dir")?,
+            "--identity" => options.identity_path = Some(value("--identity")?.into()),
+            "--trusted" => options.trust_path = Some(value("--trusted")?.into()),
+            "--trusted-only" => options.trusted_only = true,
+            "--name" => options.name = Some(value("--name")?),
+            "--hash" => options.hash_algorithm = value("--hash")?.parse()?,
+            "--progress-interval" => {
+                let millis = number("--progress-interval", value("--progress-interval")?)?;
+                options.progress_interval = Some(Duration::from_millis(millis));
+            }
+            "--wait" => options.wait = Duration::from_secs(number("--wait", value("--wait")?)?),
+            "--to" => to = Some(value("--to")?),
+            "--rotate" => rotate = true,
+            "--json" => options.json = true,
+            "-v" | "--verbose" => options.verbosity += 1,
+            "-vv" => options.verbosity += 2,
+            "-q" | "--quiet" => options.verbosity -= 1,
+            "--log-level" => options.log_filter = Some(value("--log-level")?),
+            "--control" => options.control_path = Some(value("--control")?.into()),
+            "--no-control" => options.control_path = None,
+            "-h" | "--help" => words = vec!["help".to_string()],
+            other if other.starts_with('-') => {
+                return Err(format!("Unknown option: {}", other).into())
+            }
+            _ => words.push(arg),
+        }
+    }
+
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    options.command = match words.as_slice() {
+        [] | ["listen"] | ["daemon"] => Command::Listen,
+        ["send", path] => Command::Send {
+            path: path.to_string(),
+            to: to.take(),
+        },
+        ["peers"] => Command::Peers,
+        ["status"] => Command::Status,
+        ["identity"] => Command::Identity { rotate },
+        ["trust"] | ["trust", "list"] => Command::Trust(TrustCommand::List),
+        ["trust", "add", peer, name @ ..] if !name.is_empty() => {
+            Command::Trust(TrustCommand::Add {
+                peer: peer.to_string(),
+                name: name.join(" "),
+            })
+        }
+        ["trust", "remove", peer] => Command::Trust(TrustCommand::Remove {
+            peer: peer.to_string(),
+        }),
+        ["help"] => Command::Help,
+        _ => return Err(format!("Unknown command: {}\n\n{}", words.join(" "), USAGE).into()),
+    };
+    if to.is_some() {
+        return Err("--to only works with send".into());
+    }
+    if rotate && !matches!(options.command, Command::Identity { .. }) {
+        return Err("--rotate only works with identity".into());
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, Box<dyn Error>> {
+        parse_options(args.split_whitespace().map(String::from))
+    }
+
+    fn command(args: &str) -> Command {
+        parse(args).unwrap().command
+    }
+
+    fn error(args: &str) -> String {
+        match parse(args) {
+            Ok(options) => panic!("{:?} parsed as {:?}", args, options.command),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(command(""), Command::Listen);
+        assert_eq!(command("listen"), Command::Listen);
+        assert_eq!(command("daemon"), Command::Listen);
+        assert_eq!(
+            command("send notes.txt"),
+            Command::Send {
+                path: "notes.txt".to_string(),
+                to: None
+            }
+        );
+        assert_eq!(command("peers"), Command::Peers);
+        assert_eq!(command("status"), Command::Status);
+        assert_eq!(command("identity"), Command::Identity { rotate: false });
+        assert_eq!(command("help"), Command::Help);
+    }
+
+    #[test]
+    fn test_trust_commands() {
+        assert_eq!(command("trust"), Command::Trust(TrustCommand::List));
+        assert_eq!(command("trust list"), Command::Trust(TrustCommand::List));
+        assert_eq!(
+            command("trust add QmPeer Alice Smith"),
+            Command::Trust(TrustCommand::Add {
+                peer: "QmPeer".to_string(),
+                name: "Alice Smith".to_string()
+            })
+        );
+        assert_eq!(
+            command("trust remove QmPeer"),
+            Command::Trust(TrustCommand::Remove {
+                peer: "QmPeer".to_string()
+            })
+        );
+        assert!(error("trust add QmPeer").starts_with("Unknown command: trust add QmPeer"));
+    }
+
+    #[test]
+    fn test_command_flags() {
+        assert_eq!(
+            command("--to alice send notes.txt"),
+            Command::Send {
+                path: "notes.txt".to_string(),
+                to: Some("alice".to_string())
+            }
+        );
+        assert_eq!(
+            command("send notes.txt --to alice"),
+            command("--to alice send notes.txt")
+        );
+        assert_eq!(
+            command("identity --rotate"),
+            Command::Identity { rotate: true }
+        );
+        assert_eq!(command("--help"), Command::Help);
+        assert_eq!(command("send notes.txt -h"), Command::Help);
+    }
+
+    #[test]
+    fn test_options() {
+        let options = parse(
+            "-l /ip4/127.0.0.1/tcp/4000 -d /srv/files --identity /etc/key --trusted /etc/peers \
+             --trusted-only --name alice --hash sha256 --progress-interval 250 --wait 9 --json \
+             -vv -q --log-level debug --control /run/p2pshare.sock",
+        )
+        .unwrap();
+        assert_eq!(options.command, Command::Listen);
+        assert_eq!(options.listen_address, "/ip4/127.0.0.1/tcp/4000");
+        assert_eq!(options.target_path, "/srv/files");
+        assert_eq!(options.identity_path().unwrap(), Path::new("/etc/key"));
+        assert_eq!(options.trust_path().unwrap(), Path::new("/etc/peers"));
+        assert!(options.trusted_only);
+        assert_eq!(options.name.as_deref(), Some("alice"));
+        assert_eq!(options.hash_algorithm, HashAlgorithm::Sha256);
+        assert_eq!(options.progress_interval, Some(Duration::from_millis(250)));
+        assert_eq!(options.wait, Duration::from_secs(9));
+        assert!(options.json);
+        assert_eq!(options.verbosity, 1);
+        assert_eq!(options.log_filter.as_deref(), Some("debug"));
+        assert_eq!(
+            options.control_path.as_deref(),
+            Some(Path::new("/run/p2pshare.sock"))
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        let options = parse("peers --no-control").unwrap();
+        assert_eq!(options.listen_address, DEFAULT_LISTEN_ADDRESS);
+        assert_eq!(options.target_path, DEFAULT_TARGET_PATH);
+        assert_eq!(options.hash_algorithm, HashAlgorithm::Blake3);
+        assert_eq!(options.progress_interval, None);
+        assert_eq!(options.wait, DEFAULT_WAIT);
+        assert!(!options.json && !options.trusted_only);
+        assert_eq!(options.verbosity, 0);
+        assert_eq!(options.control_path, None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(error("frobnicate").starts_with("Unknown command: frobnicate\n\nUsage:"));
+        assert!(error("send").starts_with("Unknown command: send"));
+        assert!(error("send a.txt b.txt").starts_with("Unknown command: send a.txt b.txt"));
+        assert_eq!(error("--frobnicate"), "Unknown option: --frobnicate");
+        assert_eq!(error("send notes.txt --to"), "Missing value for --to");
+        assert_eq!(error("-d"), "Missing value for --download-dir");
+        assert_eq!(error("peers --to alice"), "--to only works with send");
+        assert_eq!(
+            error("status --rotate"),
+            "--rotate only works with identity"
+        );
+        assert_eq!(error("--hash md5"), "unsupported hash algorithm \"md5\"");
+        assert_eq!(
+            error("--wait soon"),
+            "Invalid value \"soon\" for --wait: invalid digit found in string"
+        );
+        assert_eq!(
+            error("--progress-interval -5"),
+            "Invalid value \"-5\" for --progress-interval: invalid digit found in string"
+        );
+    }
+}
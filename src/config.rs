@@ -0,0 +1,39 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// Directory for the files that outlive a run, like the keypair and the trusted peers:
+/// `$XDG_CONFIG_HOME/p2pshare`, falling back to `~/.config/p2pshare`.
+pub fn config_dir() -> Option<PathBuf> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("p2pshare"))
+}
+
+/// Replaces the file at `path` with `contents`, readable by the owner only. The file is
+/// written next to the old one and renamed over it, so a failed write keeps the old one.
+///
+/// Only Unix gets the permissions, elsewhere the files get the defaults of their directory.
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    if let Some(dir) = path.parent() {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,125 @@
+use futures::prelude::*;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::header::TransferHeader;
+
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+type OpenWriter = dyn Fn(&TransferHeader) -> io::Result<BoxedWriter> + Send + Sync;
+
+/// Where the contents of an outgoing transfer come from, see
+/// `TransferBehaviour::push_source`.
+#[derive(Clone)]
+pub enum SendSource {
+    /// A file or a directory on disk.
+    Path(String),
+    /// Contents already in memory.
+    Bytes(Arc<Vec<u8>>),
+    /// Contents read while they are sent, see `SendSource::reader`.
+    Reader(SharedReader),
+}
+
+impl SendSource {
+    pub fn bytes(bytes: impl Into<Vec<u8>>) -> SendSource {
+        SendSource::Bytes(Arc::new(bytes.into()))
+    }
+
+    /// Contents read from `reader`, which can only be sent once. They are streamed and
+    /// hashed as they are read, the hash follows them like for every source.
+    ///
+    /// With a known `size`, an interrupted transfer resumes by reading past what the
+    /// receiver already has, when the source is sent again with a new reader. Without
+    /// one the contents end with the reader and are never resumed.
+    pub fn reader(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        size: Option<u64>,
+    ) -> SendSource {
+        SendSource::Reader(SharedReader {
+            reader: Arc::new(Mutex::new(Some(Box::new(reader)))),
+            size,
+        })
+    }
+}
+
+impl fmt::Debug for SendSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendSource::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            SendSource::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            SendSource::Reader(reader) => f.debug_tuple("Reader").field(reader).finish(),
+        }
+    }
+}
+
+/// A reader shared by the clones of a payload, the first transfer to start takes it.
+#[derive(Clone)]
+pub struct SharedReader {
+    reader: Arc<Mutex<Option<BoxedReader>>>,
+    pub size: Option<u64>,
+}
+
+impl SharedReader {
+    pub(crate) fn take(&self) -> io::Result<BoxedReader> {
+        self.reader
+            .lock()
+            .ok()
+            .and_then(|mut reader| reader.take())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "The reader was already sent")
+            })
+    }
+}
+
+impl fmt::Debug for SharedReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedReader")
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// Where the contents of accepted incoming files go, see
+/// `TransferBehaviour::set_receive_sink`. Directories are always written to disk, other
+/// sinks decline them.
+#[derive(Clone, Default)]
+pub enum ReceiveSink {
+    /// A file in the download directory, the path is in `ProtocolEvent::Received`.
+    #[default]
+    Disk,
+    /// A buffer, handed over as `contents` of `ProtocolEvent::Received`. Files larger
+    /// than `limit` bytes are declined, or fail once they exceed it when the sender
+    /// did not announce their size.
+    Memory { limit: u64 },
+    /// A writer opened for every file, see `ReceiveSink::writer`.
+    Writer(Arc<OpenWriter>),
+}
+
+impl ReceiveSink {
+    pub fn memory(limit: u64) -> ReceiveSink {
+        ReceiveSink::Memory { limit }
+    }
+
+    /// Writes every accepted file to the writer `open` returns for its header. The writer
+    /// is closed once the contents ended, whether or not they matched the hash.
+    pub fn writer<F, W>(open: F) -> ReceiveSink
+    where
+        F: Fn(&TransferHeader) -> io::Result<W> + Send + Sync + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        ReceiveSink::Writer(Arc::new(move |header| {
+            open(header).map(|writer| Box::new(writer) as BoxedWriter)
+        }))
+    }
+}
+
+impl fmt::Debug for ReceiveSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReceiveSink::Disk => write!(f, "Disk"),
+            ReceiveSink::Memory { limit } => write!(f, "Memory({} bytes)", limit),
+            ReceiveSink::Writer(_) => write!(f, "Writer"),
+        }
+    }
+}
@@ -0,0 +1,378 @@
+#[cfg(unix)]
+use async_std::io::BufReader;
+#[cfg(unix)]
+use async_std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use async_std::task;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net as stdnet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+use p2pshare::config::config_dir;
+use p2pshare::node::NodeError;
+
+use crate::output::Event;
+
+/// Where the control socket is created unless told otherwise.
+pub fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("control.sock"))
+}
+
+/// A JSON-RPC request read from a control client, answered by `main` against the swarm.
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// Missing for notifications, which get no response.
+    pub id: Option<Value>,
+    pub method: String,
+    pub params: Value,
+    /// Lines written back to the client: the response and, after `subscribe`, events.
+    pub client: mpsc::UnboundedSender<String>,
+}
+
+impl Call {
+    pub fn str_param(&self, name: &str) -> Result<&str, CallError> {
+        self.optional_str_param(name)?
+            .ok_or_else(|| CallError::invalid_params(format!("Missing string {:?}", name)))
+    }
+
+    pub fn optional_str_param(&self, name: &str) -> Result<Option<&str>, CallError> {
+        match self.params.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => value
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| CallError::invalid_params(format!("{:?} must be a string", name))),
+        }
+    }
+
+    pub fn optional_bool_param(&self, name: &str) -> Result<bool, CallError> {
+        match self.params.get(name) {
+            None | Some(Value::Null) => Ok(false),
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| CallError::invalid_params(format!("{:?} must be a boolean", name))),
+        }
+    }
+
+    pub fn id_param(&self, name: &str) -> Result<u64, CallError> {
+        self.params
+            .get(name)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| CallError::invalid_params(format!("Missing number {:?}", name)))
+    }
+
+    /// Sends the result, or the error, back to the client, unless the call was a
+    /// notification.
+    pub fn answer(&self, result: Result<Value, NodeError>) {
+        let id = match &self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let response = match result {
+            Ok(result) => Response::result(id, result),
+            Err(e) => {
+                let code = e.downcast_ref::<CallError>().map_or(-32000, |e| e.code);
+                Response::error(id, code, e.to_string())
+            }
+        };
+        let _ = self.client.unbounded_send(response.to_json());
+    }
+}
+
+/// A JSON-RPC error with its code, for mistakes in the request itself.
+#[derive(Debug)]
+pub struct CallError {
+    code: i32,
+    message: String,
+}
+
+impl CallError {
+    pub fn unknown_method(method: &str) -> CallError {
+        CallError {
+            code: -32601,
+            message: format!("Unknown method {:?}", method),
+        }
+    }
+
+    pub fn invalid_params(message: String) -> CallError {
+        CallError {
+            code: -32602,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for CallError {}
+
+/// A JSON-RPC response, with either `result` or `error`.
+#[derive(Serialize)]
+struct Response<'a> {
+    jsonrpc: &'static str,
+    id: &'a Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorObject>,
+}
+
+#[derive(Serialize)]
+struct ErrorObject {
+    code: i32,
+    message: String,
+}
+
+impl<'a> Response<'a> {
+    fn result(id: &'a Value, result: Value) -> Response<'a> {
+        Response {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(id: &'a Value, code: i32, message: String) -> Response<'a> {
+        Response {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(ErrorObject { code, message }),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Responses serialize")
+    }
+}
+
+/// A JSON-RPC notification, which has no id.
+#[derive(Serialize)]
+struct Notification<'a> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: &'a Event,
+}
+
+/// The line sent to subscribed clients for an event printed by `--json`.
+pub fn notification(event: &Event) -> String {
+    let notification = Notification {
+        jsonrpc: "2.0",
+        method: "event",
+        params: event,
+    };
+    serde_json::to_string(&notification).expect("Events serialize")
+}
+
+/// Creates the socket at `path` and serves every client that connects, one JSON-RPC
+/// request per line. The calls come out of the returned receiver.
+///
+/// A socket left behind by a node that did not exit cleanly is replaced, one that
+/// still answers is an error.
+#[cfg(unix)]
+pub fn listen(path: &Path) -> Result<mpsc::UnboundedReceiver<Call>, io::Error> {
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    if path.exists() {
+        if stdnet::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Another node is listening on {:?}", path),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = stdnet::UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    let listener = UnixListener::from(listener);
+
+    let (calls, receiver) = mpsc::unbounded();
+    task::spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    task::spawn(serve(stream, calls.clone()));
+                }
+                Err(e) => warn!("Control connection failed: {}", e),
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// The socket is a Unix domain socket, other platforms have no control socket.
+#[cfg(not(unix))]
+pub fn listen(path: &Path) -> Result<mpsc::UnboundedReceiver<Call>, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Cannot create {:?}, control sockets need Unix", path),
+    ))
+}
+
+#[cfg(unix)]
+async fn serve(stream: UnixStream, calls: mpsc::UnboundedSender<Call>) {
+    debug!("Control client connected");
+    let stream = Arc::new(stream);
+    let (client, mut responses) = mpsc::unbounded::<String>();
+    let writer = stream.clone();
+    task::spawn(async move {
+        while let Some(line) = responses.next().await {
+            let line = format!("{}\n", line);
+            if (&*writer).write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(&*stream).lines();
+    while let Some(Ok(line)) = lines.next().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let call = match parse_call(&line, client.clone()) {
+            Ok(call) => call,
+            Err(Some(response)) => {
+                let _ = client.unbounded_send(response);
+                continue;
+            }
+            Err(None) => continue,
+        };
+        if calls.unbounded_send(call).is_err() {
+            break;
+        }
+    }
+    debug!("Control client disconnected");
+}
+
+/// Reads a request, or returns the error response for it. Invalid notifications get no
+/// response either.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn parse_call(line: &str, client: mpsc::UnboundedSender<String>) -> Result<Call, Option<String>> {
+    let error = |id: Option<&Value>, code, message: &str| {
+        id.map(|id| Response::error(id, code, message.to_string()).to_json())
+    };
+    // serde_json stops at 128 levels of nesting, so a hostile line cannot exhaust the stack.
+    let request: Value = serde_json::from_str(line)
+        .map_err(|e| error(Some(&Value::Null), -32700, &e.to_string()))?;
+    let id = request.get("id").cloned();
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method.to_string(),
+        // Without a method it is no notification, but an invalid request.
+        None => {
+            let id = id.unwrap_or(Value::Null);
+            return Err(error(Some(&id), -32600, "Missing method"));
+        }
+    };
+    let params = match request.get("params") {
+        None => Value::Object(Default::default()),
+        Some(params @ Value::Object(_)) => params.clone(),
+        Some(_) => return Err(error(id.as_ref(), -32602, "Params must be an object")),
+    };
+    Ok(Call {
+        id,
+        method,
+        params,
+        client,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Call, Value> {
+        let (client, _) = mpsc::unbounded();
+        parse_call(line, client).map_err(|response| match response {
+            Some(response) => serde_json::from_str(&response).unwrap(),
+            None => Value::Null,
+        })
+    }
+
+    #[test]
+    fn test_parse_call() {
+        let call = parse(
+            r#"{"jsonrpc": "2.0", "id": 7, "method": "cancel", "params": {"transfer_id": 3}}"#,
+        )
+        .unwrap();
+        assert_eq!(call.id, Some(Value::from(7)));
+        assert_eq!(call.method, "cancel");
+        assert_eq!(call.id_param("transfer_id").unwrap(), 3);
+        assert!(call.str_param("path").is_err());
+        assert!(!call.optional_bool_param("wait").unwrap());
+
+        let call = parse(r#"{"id": 8, "method": "send", "params": {"wait": "yes"}}"#).unwrap();
+        assert!(call.optional_bool_param("wait").is_err());
+
+        let call = parse(r#"{"jsonrpc": "2.0", "id": "a", "method": "peers"}"#).unwrap();
+        assert_eq!(call.params, Value::Object(Default::default()));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let response = parse("{").unwrap_err();
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], -32700);
+
+        let response = parse(r#"{"id": 1}"#).unwrap_err();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], -32600);
+
+        let response = parse(r#"{"id": 1, "method": "send", "params": [1]}"#).unwrap_err();
+        assert_eq!(response["error"]["code"], -32602);
+    }
+
+    #[test]
+    fn test_notifications_get_no_response() {
+        let (client, mut responses) = mpsc::unbounded();
+        let call = parse_call(
+            r#"{"jsonrpc": "2.0", "method": "cancel", "params": {"transfer_id": 3}}"#,
+            client.clone(),
+        )
+        .unwrap();
+        assert_eq!(call.id, None);
+        call.answer(Ok(Value::Null));
+        call.answer(Err("No transfer 3".into()));
+
+        let invalid = parse_call(r#"{"method": "send", "params": 1}"#, client.clone());
+        assert!(matches!(invalid, Err(None)));
+
+        // An explicit null id is a request, not a notification.
+        let call = parse_call(r#"{"id": null, "method": "peers"}"#, client).unwrap();
+        call.answer(Ok(Value::Null));
+        let response: Value =
+            serde_json::from_str(&responses.try_next().unwrap().unwrap()).unwrap();
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response.get("result"), Some(&Value::Null));
+        assert!(response.get("error").is_none());
+        assert!(responses.try_next().is_err());
+    }
+
+    #[test]
+    fn test_deep_nesting_is_an_error() {
+        let line = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        let response = parse(&line).unwrap_err();
+        assert_eq!(response["error"]["code"], -32700);
+    }
+}
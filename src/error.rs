@@ -0,0 +1,72 @@
+use libp2p::core::UpgradeError;
+use libp2p::swarm::ProtocolsHandlerUpgrErr;
+use std::sync::Arc;
+use std::{error, fmt, io};
+
+use crate::types::ContentHash;
+
+/// Why a transfer did not complete.
+#[derive(Clone, Debug)]
+pub enum TransferError {
+    /// Reading or writing the file or the socket failed.
+    Io(Arc<io::Error>),
+    /// The remote peer sent something the protocol does not allow.
+    Protocol(String),
+    /// The contents do not hash to what the sender announced.
+    HashMismatch {
+        expected: ContentHash,
+        actual: ContentHash,
+    },
+    /// The receiver declined the transfer.
+    Rejected,
+    /// The receiver got the contents but could not keep them, because they did not
+    /// match the hash or could not be written for instance. Its own event tells why.
+    Undelivered,
+    /// The transfer was stopped by `TransferBehaviour::cancel` on our side or, for
+    /// incoming transfers, explicitly by the sender.
+    Cancelled,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::Io(e) => write!(f, "I/O error: {}", e),
+            TransferError::Protocol(message) => write!(f, "protocol violation: {}", message),
+            TransferError::HashMismatch { expected, actual } => {
+                write!(f, "hash mismatch, expected {} got {}", expected, actual)
+            }
+            TransferError::Rejected => write!(f, "rejected by the receiver"),
+            TransferError::Undelivered => write!(f, "the receiver could not keep the contents"),
+            TransferError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl error::Error for TransferError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TransferError::Io(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(Arc::new(e))
+    }
+}
+
+impl From<ProtocolsHandlerUpgrErr<TransferError>> for TransferError {
+    fn from(e: ProtocolsHandlerUpgrErr<TransferError>) -> Self {
+        match e {
+            ProtocolsHandlerUpgrErr::Timeout | ProtocolsHandlerUpgrErr::Timer => {
+                io::Error::new(io::ErrorKind::TimedOut, "transfer timed out").into()
+            }
+            ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Select(e)) => {
+                TransferError::Protocol(format!("negotiation failed: {}", e))
+            }
+            ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(e)) => e,
+        }
+    }
+}
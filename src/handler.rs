@@ -22,6 +22,7 @@ use libp2p::swarm::protocols_handler::{
     InboundUpgradeSend, KeepAlive, OutboundUpgradeSend, ProtocolsHandler, ProtocolsHandlerEvent,
     ProtocolsHandlerUpgrErr, SubstreamProtocol,
 };
+use tracing::debug;
 
 // use smallvec::Vec;
 use std::{error, task::Context, task::Poll, time::Duration};
@@ -32,6 +33,9 @@ use std::time::Instant;
 /// Implementation of `ProtocolsHandler` that opens a new substream for each individual message.
 ///
 /// This struct is meant to be a helper for other implementations to use.
+///
+/// Unlike the libp2p original, a failed outbound upgrade does not close the connection,
+/// it is reported as an event built from the upgrade and the error.
 // TODO: Debug
 pub struct OneShotHandler<TInProto, TOutProto, TOutEvent>
 where
@@ -39,8 +43,6 @@ where
 {
     /// The upgrade for inbound substreams.
     listen_protocol: SubstreamProtocol<TInProto>,
-    /// If `Some`, something bad happened and we should shut down the handler with an error.
-    pending_error: Option<ProtocolsHandlerUpgrErr<<TOutProto as OutboundUpgradeSend>::Error>>,
     /// Queue of events to produce in `poll()`.
     events_out: Vec<TOutEvent>,
     /// Queue of outbound substreams to open.
@@ -54,6 +56,8 @@ where
     /// After the given duration has elapsed, an inactive connection will shutdown.
     inactive_timeout: Duration,
     substream_timeout: Duration,
+    /// Set by `close()`, inbound substreams are ignored and never keep the connection.
+    closed: bool,
 }
 
 impl<TInProto, TOutProto, TOutEvent> OneShotHandler<TInProto, TOutProto, TOutEvent>
@@ -69,7 +73,6 @@ where
     ) -> Self {
         OneShotHandler {
             listen_protocol,
-            pending_error: None,
             events_out: Vec::new(),
             dial_queue: Vec::new(),
             dial_negotiated: 0,
@@ -77,6 +80,7 @@ where
             keep_alive: KeepAlive::Yes,
             inactive_timeout,
             substream_timeout,
+            closed: false,
         }
     }
 
@@ -104,6 +108,24 @@ where
         &mut self.listen_protocol
     }
 
+    /// Updates the keep alive once an outbound substream is done, successfully or not.
+    fn outbound_finished(&mut self) {
+        self.dial_negotiated -= 1;
+        if self.closed {
+            self.keep_alive = KeepAlive::No;
+        } else if self.dial_negotiated == 0 && self.dial_queue.is_empty() {
+            self.keep_alive = KeepAlive::Until(Instant::now() + self.inactive_timeout);
+        }
+    }
+
+    /// Lets the connection close as soon as no other handler needs it. Whatever the remote
+    /// sends from now on is dropped instead of being reported.
+    #[inline]
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.keep_alive = KeepAlive::No;
+    }
+
     /// Opens an outbound substream with `upgrade`.
     #[inline]
     pub fn send_request(&mut self, upgrade: TOutProto) {
@@ -131,19 +153,20 @@ impl<TInProto, TOutProto, TOutEvent> ProtocolsHandler
     for OneShotHandler<TInProto, TOutProto, TOutEvent>
 where
     TInProto: InboundUpgradeSend + Send + 'static,
-    TOutProto: OutboundUpgradeSend,
+    TOutProto: OutboundUpgradeSend + Clone,
     TInProto::Output: Into<TOutEvent>,
     TOutProto::Output: Into<TOutEvent>,
     TOutProto::Error: error::Error + Send + 'static,
     SubstreamProtocol<TInProto>: Clone,
-    TOutEvent: Send + 'static,
+    TOutEvent: From<(TOutProto, ProtocolsHandlerUpgrErr<TOutProto::Error>)> + Send + 'static,
 {
     type InEvent = TOutProto;
     type OutEvent = TOutEvent;
     type Error = ProtocolsHandlerUpgrErr<<Self::OutboundProtocol as OutboundUpgradeSend>::Error>;
     type InboundProtocol = TInProto;
     type OutboundProtocol = TOutProto;
-    type OutboundOpenInfo = ();
+    /// The upgrade itself, so a failure can be reported together with what failed.
+    type OutboundOpenInfo = TOutProto;
 
     #[inline]
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
@@ -155,6 +178,11 @@ where
         &mut self,
         out: <Self::InboundProtocol as InboundUpgradeSend>::Output,
     ) {
+        if self.closed {
+            debug!("Ignoring an inbound substream on a closed connection");
+            return;
+        }
+
         // If we're shutting down the connection for inactivity, reset the timeout.
         if !self.keep_alive.is_yes() {
             self.keep_alive = KeepAlive::Until(Instant::now() + self.inactive_timeout);
@@ -169,11 +197,7 @@ where
         out: <Self::OutboundProtocol as OutboundUpgradeSend>::Output,
         _: Self::OutboundOpenInfo,
     ) {
-        self.dial_negotiated -= 1;
-        if self.dial_negotiated == 0 && self.dial_queue.is_empty() {
-            self.keep_alive = KeepAlive::Until(Instant::now() + self.inactive_timeout);
-        }
-
+        self.outbound_finished();
         self.events_out.push(out.into());
     }
 
@@ -185,13 +209,12 @@ where
     #[inline]
     fn inject_dial_upgrade_error(
         &mut self,
-        _: Self::OutboundOpenInfo,
+        upgrade: Self::OutboundOpenInfo,
         error: ProtocolsHandlerUpgrErr<<Self::OutboundProtocol as OutboundUpgradeSend>::Error>,
     ) {
-        println!("inject_dial_upgrade_error {:?}", error);
-        if self.pending_error.is_none() {
-            self.pending_error = Some(error);
-        }
+        debug!("inject_dial_upgrade_error {:?}", error);
+        self.outbound_finished();
+        self.events_out.push((upgrade, error).into());
     }
 
     #[inline]
@@ -210,11 +233,6 @@ where
             Self::Error,
         >,
     > {
-        if let Some(err) = self.pending_error.take() {
-            println!("Error: {:?}", err);
-            return Poll::Ready(ProtocolsHandlerEvent::Close(err));
-        }
-
         if !self.events_out.is_empty() {
             return Poll::Ready(ProtocolsHandlerEvent::Custom(self.events_out.remove(0)));
         } else {
@@ -224,10 +242,11 @@ where
         if !self.dial_queue.is_empty() {
             if self.dial_negotiated < self.max_dial_negotiated {
                 self.dial_negotiated += 1;
+                let upgrade = self.dial_queue.remove(0);
                 return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
-                    protocol: SubstreamProtocol::new(self.dial_queue.remove(0))
+                    protocol: SubstreamProtocol::new(upgrade.clone())
                         .with_timeout(self.substream_timeout),
-                    info: (),
+                    info: upgrade,
                 });
             }
         } else {
@@ -237,3 +256,29 @@ where
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ProtocolEvent, TransferPayload};
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn test_closed_handler_ignores_inbound() {
+        let mut handler = OneShotHandler::<TransferPayload, TransferPayload, ProtocolEvent>::new(
+            SubstreamProtocol::new(TransferPayload::default()),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+        );
+        handler.close();
+        handler.inject_fully_negotiated_inbound(ProtocolEvent::Sent {
+            transfer_id: 1,
+            name: "notes.txt".to_string(),
+            broadcast: None,
+        });
+
+        assert!(matches!(handler.connection_keep_alive(), KeepAlive::No));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(handler.poll(&mut cx).is_pending());
+    }
+}
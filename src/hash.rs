@@ -0,0 +1,138 @@
+use sha2::Digest;
+use std::{error, fmt, str::FromStr};
+
+/// Algorithms a sender may declare in the transfer header.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    #[default]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Name used on the wire and on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = UnsupportedAlgorithm;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            other => Err(UnsupportedAlgorithm(other.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsupportedAlgorithm(pub String);
+
+impl fmt::Display for UnsupportedAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unsupported hash algorithm {:?}", self.0)
+    }
+}
+
+impl error::Error for UnsupportedAlgorithm {}
+
+/// Incremental hasher for one of the supported algorithms, producing a hex digest.
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Blake3(hasher) => blake3::Hasher::finalize(&hasher).to_hex().to_string(),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algorithm: HashAlgorithm, chunks: &[&[u8]]) -> String {
+        let mut hasher = algorithm.hasher();
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_sha256_known_answers() {
+        assert_eq!(
+            digest(HashAlgorithm::Sha256, &[]),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            digest(HashAlgorithm::Sha256, &[b"a", b"bc"]),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_blake3_known_answers() {
+        assert_eq!(
+            digest(HashAlgorithm::Blake3, &[]),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            digest(HashAlgorithm::Blake3, &[b"a", b"bc"]),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[test]
+    fn test_algorithm_names_round_trip() {
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            assert_eq!(algorithm.to_string(), algorithm.name());
+            assert_eq!(algorithm.name().parse::<HashAlgorithm>(), Ok(algorithm));
+        }
+        assert_eq!(HashAlgorithm::default(), HashAlgorithm::Blake3);
+    }
+
+    #[test]
+    fn test_unsupported_algorithm() {
+        let error = "sha1".parse::<HashAlgorithm>().unwrap_err();
+        assert_eq!(error, UnsupportedAlgorithm("sha1".to_string()));
+        assert_eq!(error.to_string(), "unsupported hash algorithm \"sha1\"");
+        assert!("BLAKE3".parse::<HashAlgorithm>().is_err());
+    }
+}
@@ -0,0 +1,385 @@
+use futures::prelude::*;
+use std::convert::TryFrom;
+use std::io;
+
+use crate::hash::Hasher;
+
+/// Version byte written at the start of every `/transfer/2.0` header.
+pub const HEADER_VERSION: u8 = 2;
+
+/// `size` of contents whose length the sender does not know until they end.
+pub const UNKNOWN_SIZE: u64 = u64::MAX;
+
+/// Upper bound for the optional metadata blob, so a peer cannot make us allocate arbitrarily.
+const MAX_METADATA_LEN: u32 = 64 * 1024;
+
+/// Upper bounds for the manifest of a directory, which is read before the offer is answered.
+const MAX_ENTRIES: u32 = 100_000;
+const MAX_MANIFEST_LEN: usize = 16 * 1024 * 1024;
+
+/// Header sent in front of the file contents.
+///
+/// Every variable length field is prefixed with its length (big endian), so names may contain
+/// any character, newlines included.
+///
+/// ```text
+/// version: u8
+/// name:    u16 length, UTF-8 bytes
+/// size:    u64, `UNKNOWN_SIZE` when the sender does not know it
+/// algo:    u8 length, UTF-8 bytes
+/// hash:    u16 length, UTF-8 bytes
+/// meta:    u8 flag (0 = none, 1 = present), then u32 length and bytes when present
+/// entries: u32 count, then for every entry a u16 length and UTF-8 path, and a u64 size
+/// ```
+///
+/// A header with entries announces a directory: the contents are its files, one after
+/// another in the order of the entries. `size` covers all of them, `hash` covers the
+/// entries as well, see `hash_manifest`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransferHeader {
+    pub name: String,
+    pub size: u64,
+    pub hash_algorithm: String,
+    pub hash: String,
+    pub metadata: Option<Vec<u8>>,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A file of a directory transfer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ManifestEntry {
+    /// Path relative to the sent directory, with `/` separated components.
+    pub path: String,
+    pub size: u64,
+}
+
+impl TransferHeader {
+    /// `size`, unless it is `UNKNOWN_SIZE`.
+    pub fn known_size(&self) -> Option<u64> {
+        Some(self.size).filter(|size| *size != UNKNOWN_SIZE)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, io::Error> {
+        let mut bytes = vec![HEADER_VERSION];
+        write_field(&mut bytes, self.name.as_bytes(), u16::MAX as usize, 2)?;
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        write_field(
+            &mut bytes,
+            self.hash_algorithm.as_bytes(),
+            u8::MAX as usize,
+            1,
+        )?;
+        write_field(&mut bytes, self.hash.as_bytes(), u16::MAX as usize, 2)?;
+        match &self.metadata {
+            Some(metadata) => {
+                bytes.push(1);
+                write_field(&mut bytes, metadata, MAX_METADATA_LEN as usize, 4)?;
+            }
+            None => bytes.push(0),
+        }
+        let count = u32::try_from(self.entries.len()).map_err(|e| invalid_data(e.to_string()))?;
+        if count > MAX_ENTRIES {
+            return Err(invalid_data(format!("Too many entries: {}", count)));
+        }
+        bytes.extend_from_slice(&count.to_be_bytes());
+        let mut manifest_len = 0;
+        for entry in &self.entries {
+            manifest_len += entry.path.len();
+            if manifest_len > MAX_MANIFEST_LEN {
+                return Err(invalid_data(format!(
+                    "Manifest too long: over {} bytes",
+                    MAX_MANIFEST_LEN
+                )));
+            }
+            write_field(&mut bytes, entry.path.as_bytes(), u16::MAX as usize, 2)?;
+            bytes.extend_from_slice(&entry.size.to_be_bytes());
+        }
+        Ok(bytes)
+    }
+
+    pub async fn read_from(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self, io::Error> {
+        let version = read_u8(reader).await?;
+        if version != HEADER_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported header version {}",
+                version
+            )));
+        }
+
+        let len = read_u16(reader).await?;
+        let name = read_string(reader, len as usize).await?;
+
+        let size = read_u64(reader).await?;
+
+        let len = read_u8(reader).await?;
+        let hash_algorithm = read_string(reader, len as usize).await?;
+
+        let len = read_u16(reader).await?;
+        let hash = read_string(reader, len as usize).await?;
+
+        let metadata = match read_u8(reader).await? {
+            0 => None,
+            1 => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len).await?;
+                let len = u32::from_be_bytes(len);
+                if len > MAX_METADATA_LEN {
+                    return Err(invalid_data(format!("Metadata too long: {} bytes", len)));
+                }
+                let mut metadata = vec![0u8; len as usize];
+                reader.read_exact(&mut metadata).await?;
+                Some(metadata)
+            }
+            flag => return Err(invalid_data(format!("Invalid metadata flag {}", flag))),
+        };
+
+        let mut count = [0u8; 4];
+        reader.read_exact(&mut count).await?;
+        let count = u32::from_be_bytes(count);
+        if count > MAX_ENTRIES {
+            return Err(invalid_data(format!("Too many entries: {}", count)));
+        }
+        let mut entries = vec![];
+        let mut manifest_len = 0;
+        for _ in 0..count {
+            let len = read_u16(reader).await?;
+            manifest_len += len as usize;
+            if manifest_len > MAX_MANIFEST_LEN {
+                return Err(invalid_data(format!(
+                    "Manifest too long: over {} bytes",
+                    MAX_MANIFEST_LEN
+                )));
+            }
+            let path = read_string(reader, len as usize).await?;
+            let size = read_u64(reader).await?;
+            entries.push(ManifestEntry { path, size });
+        }
+
+        Ok(TransferHeader {
+            name,
+            size,
+            hash_algorithm,
+            hash,
+            metadata,
+            entries,
+        })
+    }
+}
+
+/// Feeds `entries` to `hasher` the way they are encoded in the header, ahead of the
+/// contents of a directory, so its hash also covers the paths and sizes of its files.
+/// Does nothing for a single file.
+pub fn hash_manifest(entries: &[ManifestEntry], hasher: &mut Hasher) {
+    if entries.is_empty() {
+        return;
+    }
+    hasher.update(&(entries.len() as u32).to_be_bytes());
+    for entry in entries {
+        hasher.update(&(entry.path.len() as u16).to_be_bytes());
+        hasher.update(entry.path.as_bytes());
+        hasher.update(&entry.size.to_be_bytes());
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_field(
+    bytes: &mut Vec<u8>,
+    value: &[u8],
+    max_len: usize,
+    prefix_len: usize,
+) -> Result<(), io::Error> {
+    if value.len() > max_len {
+        return Err(invalid_data(format!(
+            "Header field too long: {} bytes",
+            value.len()
+        )));
+    }
+    let len = u32::try_from(value.len()).map_err(|e| invalid_data(e.to_string()))?;
+    bytes.extend_from_slice(&len.to_be_bytes()[4 - prefix_len..]);
+    bytes.extend_from_slice(value);
+    Ok(())
+}
+
+async fn read_u8(reader: &mut (impl AsyncRead + Unpin)) -> Result<u8, io::Error> {
+    let mut buff = [0u8; 1];
+    reader.read_exact(&mut buff).await?;
+    Ok(buff[0])
+}
+
+async fn read_u16(reader: &mut (impl AsyncRead + Unpin)) -> Result<u16, io::Error> {
+    let mut buff = [0u8; 2];
+    reader.read_exact(&mut buff).await?;
+    Ok(u16::from_be_bytes(buff))
+}
+
+async fn read_u64(reader: &mut (impl AsyncRead + Unpin)) -> Result<u64, io::Error> {
+    let mut buff = [0u8; 8];
+    reader.read_exact(&mut buff).await?;
+    Ok(u64::from_be_bytes(buff))
+}
+
+async fn read_string(
+    reader: &mut (impl AsyncRead + Unpin),
+    len: usize,
+) -> Result<String, io::Error> {
+    let mut buff = vec![0u8; len];
+    reader.read_exact(&mut buff).await?;
+    String::from_utf8(buff).map_err(|e| invalid_data(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn header() -> TransferHeader {
+        TransferHeader {
+            name: "notes\nfinal.txt".to_string(),
+            size: 12,
+            hash_algorithm: "blake3".to_string(),
+            hash: "af1349b9".to_string(),
+            metadata: Some(vec![0, 1, 2]),
+            entries: vec![
+                ManifestEntry {
+                    path: "a.txt".to_string(),
+                    size: 5,
+                },
+                ManifestEntry {
+                    path: "sub/b.txt".to_string(),
+                    size: 7,
+                },
+            ],
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<TransferHeader, io::Error> {
+        block_on(TransferHeader::read_from(&mut &bytes[..]))
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = header();
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(bytes[0], HEADER_VERSION);
+        assert_eq!(decode(&bytes).unwrap(), header);
+
+        let plain = TransferHeader {
+            metadata: None,
+            entries: vec![],
+            ..header
+        };
+        assert_eq!(decode(&plain.to_bytes().unwrap()).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let bytes = header().to_bytes().unwrap();
+        for len in 0..bytes.len() {
+            let error = decode(&bytes[..len]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_oversized_fields() {
+        let long_name = TransferHeader {
+            name: "x".repeat(u16::MAX as usize + 1),
+            ..header()
+        };
+        assert!(long_name.to_bytes().is_err());
+
+        let long_metadata = TransferHeader {
+            metadata: Some(vec![0; MAX_METADATA_LEN as usize + 1]),
+            ..header()
+        };
+        assert!(long_metadata.to_bytes().is_err());
+
+        // A hand made header announcing more metadata than we accept.
+        let mut bytes = TransferHeader {
+            metadata: None,
+            entries: vec![],
+            ..header()
+        }
+        .to_bytes()
+        .unwrap();
+        bytes.truncate(bytes.len() - 5);
+        bytes.push(1);
+        bytes.extend_from_slice(&(MAX_METADATA_LEN + 1).to_be_bytes());
+        let error = decode(&bytes).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_manifest_limits() {
+        let entry = ManifestEntry {
+            path: "f".to_string(),
+            size: 0,
+        };
+        let too_many = TransferHeader {
+            entries: vec![entry; MAX_ENTRIES as usize + 1],
+            ..header()
+        };
+        assert!(too_many.to_bytes().is_err());
+
+        let mut bytes = TransferHeader {
+            entries: vec![],
+            ..header()
+        }
+        .to_bytes()
+        .unwrap();
+        bytes.truncate(bytes.len() - 4);
+        bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            decode(&bytes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Long paths adding up to more than the manifest may take, the reader stops at the
+        // first path over the limit instead of reading them all.
+        let path = "p".repeat(u16::MAX as usize);
+        let count = MAX_MANIFEST_LEN / path.len() + 1;
+        let long = TransferHeader {
+            entries: vec![
+                ManifestEntry {
+                    path: path.clone(),
+                    size: 0,
+                };
+                count
+            ],
+            ..header()
+        };
+        assert!(long.to_bytes().is_err());
+        let mut bytes = bytes[..bytes.len() - 4].to_vec();
+        bytes.extend_from_slice(&(count as u32).to_be_bytes());
+        for _ in 0..count {
+            bytes.extend_from_slice(&(path.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(path.as_bytes());
+            bytes.extend_from_slice(&0u64.to_be_bytes());
+        }
+        assert_eq!(
+            decode(&bytes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let mut bytes = header().to_bytes().unwrap();
+        bytes[0] = HEADER_VERSION + 1;
+        assert_eq!(
+            decode(&bytes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // The name is not UTF-8.
+        let mut bytes = header().to_bytes().unwrap();
+        bytes[3] = 0xff;
+        assert_eq!(
+            decode(&bytes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,36 @@
+use libp2p::core::identity::{ed25519, Keypair};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::{config_dir, write_private};
+
+/// Where the keypair lives unless told otherwise.
+pub fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("identity"))
+}
+
+/// Loads the keypair stored at `path`, generating and storing a new one the first time,
+/// so the `PeerId` stays the same across restarts.
+pub fn load_or_generate(path: &Path) -> Result<Keypair, io::Error> {
+    match fs::read(path) {
+        Ok(mut bytes) => ed25519::Keypair::decode(&mut bytes)
+            .map(Keypair::Ed25519)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid keypair in {:?}: {}", path, e),
+                )
+            }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => generate(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the keypair stored at `path` with a new one. Peers that knew the old
+/// `PeerId` will no longer recognise this node.
+pub fn generate(path: &Path) -> Result<Keypair, io::Error> {
+    let keypair = ed25519::Keypair::generate();
+    write_private(path, &keypair.encode())?;
+    Ok(Keypair::Ed25519(keypair))
+}
@@ -1,3 +1,16 @@
 pub mod behaviour;
+pub mod config;
+pub mod contents;
+pub mod error;
 pub mod handler;
+pub mod hash;
+pub mod header;
+pub mod keypair;
+pub mod node;
+pub mod peer_info;
 pub mod protocol;
+pub mod sanitize;
+pub mod trust;
+pub mod types;
+
+pub use node::{Node, NodeBuilder, NodeEvent};
@@ -0,0 +1,47 @@
+use std::io::{self, IsTerminal};
+
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable with the filter to use when `--log-level` is not given.
+pub const LOG_ENV: &str = "P2PSHARE_LOG";
+
+/// Levels from quietest to most verbose, `-v` and `-q` move along them from `info`.
+const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// The default filter with this crate's records made more or less verbose, each step
+/// of `verbosity` being one level. Without flags it is `warn,p2pshare=info`: libp2p is
+/// mostly noise unless it fails.
+pub fn verbosity_filter(verbosity: i8) -> String {
+    let level = LEVELS[(3 + verbosity).clamp(0, 5) as usize];
+    // Quieter than the default lowers the level of everything else too.
+    let default = if verbosity < -1 { level } else { "warn" };
+    format!("{},p2pshare={}", default, level)
+}
+
+/// Logs to stderr, keeping stdout for the output of the commands. `spec` is a filter
+/// like `info,p2pshare::protocol=debug`, see `EnvFilter`. Records of the `log` crate,
+/// which libp2p uses, go through the same filter.
+pub fn init(spec: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(spec).map_err(|e| format!("Invalid log filter: {}", e))?;
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .try_init()
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_filter() {
+        assert_eq!(verbosity_filter(0), "warn,p2pshare=info");
+        assert_eq!(verbosity_filter(2), "warn,p2pshare=trace");
+        assert_eq!(verbosity_filter(9), "warn,p2pshare=trace");
+        assert_eq!(verbosity_filter(-1), "warn,p2pshare=warn");
+        assert_eq!(verbosity_filter(-2), "error,p2pshare=error");
+        assert_eq!(verbosity_filter(-9), "off,p2pshare=off");
+    }
+}
@@ -1,136 +1,901 @@
 use async_std::{io, task};
-use futures::{executor, future, prelude::*};
-use libp2p::{
-    build_development_transport,
-    core::transport::timeout::TransportTimeout,
-    identity,
-    mdns::{Mdns, MdnsEvent},
-    swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour, PeerId, Swarm,
-};
+use futures::{channel::mpsc, prelude::*, select, stream};
+use futures_timer::Delay;
+use libp2p::PeerId;
+use serde_json::{json, Value};
+use tracing::error;
 
 use std::{
+    collections::HashMap,
+    env,
     error::Error,
-    task::{Context, Poll},
+    io::{self as stdio, Write},
+    process,
     time::Duration,
 };
 
-mod behaviour;
-mod handler;
-mod protocol;
+use p2pshare::behaviour::{Direction, TransferState, TransferStatus};
+use p2pshare::keypair;
+use p2pshare::node::{Node, NodeBuilder, NodeError, NodeEvent, Peer};
+use p2pshare::peer_info::PeerInfo;
+use p2pshare::protocol::ProtocolEvent;
+use p2pshare::trust::TrustStore;
+
+mod cli;
+mod control;
+mod logger;
+mod output;
+
+use cli::{Command, Options, TrustCommand, USAGE};
+use control::{Call, CallError};
+use output::{Event, PeerFields, PeerStatus, TrustedPeer};
 
-use behaviour::TransferBehaviour;
-use protocol::{ProtocolEvent, TransferPayload};
+const PROGRESS_BAR_WIDTH: usize = 30;
 
-#[derive(NetworkBehaviour)]
-struct MyBehaviour {
-    mdns: Mdns,
-    transfer_behaviour: TransferBehaviour,
+/// The command line front end of a running `Node`: prints what happens and runs the
+/// commands typed on stdin or sent over the control socket.
+struct App {
+    node: Node,
+    /// Whether events are printed as JSON lines.
+    json: bool,
+    /// What the peers announced about themselves, to describe them in the output.
+    peer_info: HashMap<PeerId, PeerInfo>,
+    /// Control clients that asked for events, see `control::notification`.
+    subscribers: Vec<mpsc::UnboundedSender<String>>,
+    /// `send` calls answered once their transfer ends, by transfer id.
+    waiting: HashMap<u64, Call>,
 }
 
-impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
-    fn inject_event(&mut self, event: MdnsEvent) {
+impl App {
+    /// Prints an event in `--json` mode and sends it to the subscribed control clients.
+    fn report(&mut self, event: Event) {
+        if !self.subscribers.is_empty() {
+            let line = control::notification(&event);
+            self.subscribers
+                .retain(|client| client.unbounded_send(line.clone()).is_ok());
+        }
+        if self.json {
+            event.print();
+        }
+    }
+
+    /// Reports `event`. Returns the id of the transfer it ends and whether it succeeded.
+    fn handle_event(&mut self, event: NodeEvent) -> Option<(u64, bool)> {
         match event {
-            MdnsEvent::Discovered(list) => {
-                for (peer, _addr) in list {
-                    self.transfer_behaviour.peers.insert(peer);
+            NodeEvent::Listening(address) => {
+                if self.json {
+                    Event::Listening {
+                        address: address.to_string(),
+                    }
+                    .print();
+                } else {
+                    println!("Listening on {:?}", address);
                 }
             }
-            MdnsEvent::Expired(list) => {
-                for (peer, _addr) in list {
-                    println!("Expired: {:?}", peer);
-                    self.transfer_behaviour.peers.remove(&peer);
+            NodeEvent::Discovered(peer) => {
+                let event = Event::Discovered(self.peer_fields(&peer));
+                self.report(event);
+            }
+            NodeEvent::Identified { peer, info } => {
+                self.peer_info.insert(peer.clone(), info);
+                let event = Event::Identified(self.peer_fields(&peer));
+                self.report(event);
+                if !self.json {
+                    println!("Discovered: {}", self.describe_peer(&peer));
                 }
             }
+            NodeEvent::Expired(peer) => {
+                let event = Event::Expired(self.peer_fields(&peer));
+                self.report(event);
+                if !self.json {
+                    println!("Expired: {}", self.describe_peer(&peer));
+                }
+            }
+            NodeEvent::Transfer(event) => {
+                let finished = event.finished_transfer().map(|id| {
+                    let succeeded = matches!(
+                        event,
+                        ProtocolEvent::Received { .. } | ProtocolEvent::Sent { .. }
+                    );
+                    (id, succeeded)
+                });
+                let json = self.event_json(&event);
+                if let Some(call) = finished.and_then(|(id, _)| self.waiting.remove(&id)) {
+                    call.answer(Ok(output::to_value(&json)));
+                }
+                self.report(json);
+                if !self.json {
+                    self.print_event(event);
+                }
+                return finished;
+            }
         }
+        None
     }
-}
 
-impl NetworkBehaviourEventProcess<ProtocolEvent> for MyBehaviour {
-    fn inject_event(&mut self, event: ProtocolEvent) {
+    fn print_event(&self, event: ProtocolEvent) {
         match event {
             ProtocolEvent::Received {
                 name,
                 path,
                 hash,
+                hash_algorithm,
+                size_bytes,
+                verified,
+                ..
+            } => {
+                println!(
+                    "Inject: Data: {} {} {}:{} {}",
+                    name, path, hash_algorithm, hash, size_bytes
+                );
+                if verified {
+                    println!("File is correct")
+                } else {
+                    println!("File could not be verified: {}", path)
+                }
+            }
+            ProtocolEvent::InvalidName { name, error, .. } => {
+                error!("Refused file {:?}: {}", name, error)
+            }
+            ProtocolEvent::IncomingOffer {
+                id,
+                peer,
+                name,
                 size_bytes,
-            } => println!("Inject: Data: {} {} {} {}", name, path, hash, size_bytes),
-            ProtocolEvent::Sent => println!("sent!"),
+                ..
+            } => {
+                let from = match self.peer_info.get(&peer) {
+                    Some(info) => info.to_string(),
+                    None => format!("{:?}", peer),
+                };
+                println!(
+                    "{} wants to send {} ({} bytes). Type \"accept {}\" or \"reject {}\"",
+                    from, name, size_bytes, id, id
+                )
+            }
+            ProtocolEvent::Declined { name, .. } => println!("Declined: {}", name),
+            ProtocolEvent::Failed { name, error, .. } => match name {
+                Some(name) => error!("Transfer of {} failed: {}", name, error),
+                None => error!("Transfer failed: {}", error),
+            },
+            ProtocolEvent::Sent { name, .. } => println!("Sent {}", name),
+            ProtocolEvent::BroadcastProgress {
+                id,
+                peer,
+                result,
+                done,
+                total,
+            } => match result {
+                Ok(_) => println!("Broadcast {}: {}/{} sent to {:?}", id, done, total, peer),
+                Err(e) => println!(
+                    "Broadcast {}: {}/{} failed for {:?}: {}",
+                    id, done, total, peer, e
+                ),
+            },
+            ProtocolEvent::BroadcastFinished {
+                id,
+                name,
+                sent,
+                failed,
+            } => println!(
+                "Broadcast {} of {} finished, sent: {:?}, failed: {:?}",
+                id, name, sent, failed
+            ),
+            ProtocolEvent::Progress {
+                transfer_id,
+                bytes_done,
+                bytes_total,
+                rate,
+            } => print_progress(transfer_id, bytes_done, bytes_total, rate),
+            ProtocolEvent::Cancelled {
+                transfer_id, name, ..
+            } => println!("Transfer {} of {} cancelled", transfer_id, name),
+            ProtocolEvent::Connected { peer } => {
+                println!("Connected: {}", self.describe_peer(&peer))
+            }
+            ProtocolEvent::Disconnected { peer } => {
+                println!("Disconnected: {}", self.describe_peer(&peer))
+            }
         }
     }
-}
 
-impl NetworkBehaviourEventProcess<TransferPayload> for MyBehaviour {
-    fn inject_event(&mut self, event: TransferPayload) {
-        println!("TransferPayload event: {:?}", event);
-        match event.check_file() {
-            Ok(_) => println!("File is correct"),
-            Err(e) => println!("{:?}", e),
+    /// The line printed for `event` in `--json` mode.
+    fn event_json(&self, event: &ProtocolEvent) -> Event {
+        match event {
+            ProtocolEvent::Received {
+                transfer_id,
+                name,
+                path,
+                hash,
+                hash_algorithm,
+                size_bytes,
+                verified,
+                ..
+            } => Event::Received {
+                transfer_id: *transfer_id,
+                name: name.to_string(),
+                path: path.to_string(),
+                hash: hash.to_string(),
+                hash_algorithm: hash_algorithm.clone(),
+                size_bytes: *size_bytes as u64,
+                verified: *verified,
+            },
+            ProtocolEvent::InvalidName {
+                transfer_id,
+                name,
+                error,
+            } => Event::InvalidName {
+                transfer_id: *transfer_id,
+                name: name.clone(),
+                error: error.to_string(),
+            },
+            ProtocolEvent::IncomingOffer {
+                id,
+                peer,
+                name,
+                hash,
+                size_bytes,
+            } => Event::Offer {
+                peer: self.peer_fields(peer),
+                transfer_id: *id,
+                name: name.clone(),
+                hash: hash.clone(),
+                size_bytes: *size_bytes as u64,
+            },
+            ProtocolEvent::Declined { transfer_id, name } => Event::Declined {
+                transfer_id: *transfer_id,
+                name: name.clone(),
+            },
+            ProtocolEvent::Sent {
+                transfer_id,
+                name,
+                broadcast,
+            } => Event::Sent {
+                transfer_id: *transfer_id,
+                name: name.clone(),
+                broadcast: *broadcast,
+            },
+            ProtocolEvent::Failed {
+                transfer_id,
+                name,
+                broadcast,
+                error,
+            } => Event::Failed {
+                transfer_id: *transfer_id,
+                name: name.clone(),
+                broadcast: *broadcast,
+                error: error.to_string(),
+            },
+            ProtocolEvent::BroadcastProgress {
+                id,
+                peer,
+                result,
+                done,
+                total,
+            } => Event::BroadcastProgress {
+                peer: self.peer_fields(peer),
+                broadcast: *id,
+                ok: result.is_ok(),
+                error: result.as_ref().err().map(ToString::to_string),
+                done: *done as u64,
+                total: *total as u64,
+            },
+            ProtocolEvent::BroadcastFinished {
+                id,
+                name,
+                sent,
+                failed,
+            } => Event::BroadcastFinished {
+                broadcast: *id,
+                name: name.clone(),
+                sent: sent.iter().map(PeerId::to_base58).collect(),
+                failed: failed.iter().map(PeerId::to_base58).collect(),
+            },
+            ProtocolEvent::Progress {
+                transfer_id,
+                bytes_done,
+                bytes_total,
+                rate,
+            } => Event::Progress {
+                transfer_id: *transfer_id,
+                bytes_done: *bytes_done,
+                bytes_total: *bytes_total,
+                rate: *rate,
+            },
+            ProtocolEvent::Cancelled {
+                transfer_id,
+                name,
+                broadcast,
+            } => Event::Cancelled {
+                transfer_id: *transfer_id,
+                name: name.clone(),
+                broadcast: *broadcast,
+            },
+            ProtocolEvent::Connected { peer } => Event::Connected(self.peer_fields(peer)),
+            ProtocolEvent::Disconnected { peer } => Event::Disconnected(self.peer_fields(peer)),
+        }
+    }
+
+    /// The id of `peer` and, once it identified itself, its name and host name.
+    fn peer_fields(&self, peer: &PeerId) -> PeerFields {
+        let info = self.peer_info.get(peer);
+        PeerFields {
+            peer: peer.to_base58(),
+            peer_name: info.map(|info| info.name.clone()),
+            peer_host: info.map(|info| info.hostname.clone()),
+        }
+    }
+
+    fn describe_peer(&self, peer: &PeerId) -> String {
+        match self.peer_info.get(peer) {
+            Some(info) => format!("{} {}", info, peer.to_base58()),
+            None => peer.to_base58(),
+        }
+    }
+
+    fn peers_json(&self, peers: &[Peer]) -> Vec<PeerStatus> {
+        peers
+            .iter()
+            .map(|peer| PeerStatus {
+                peer: self.peer_fields(&peer.id),
+                connected: peer.connected,
+                trusted_as: peer.trusted_as.clone(),
+            })
+            .collect()
+    }
+
+    async fn print_peers(&self) -> Result<(), NodeError> {
+        let peers = self.node.peers().await?;
+        if self.json {
+            Event::Peers {
+                peers: self.peers_json(&peers),
+            }
+            .print();
+            return Ok(());
+        }
+        if peers.is_empty() {
+            println!("No peers found");
+        }
+        for peer in peers {
+            let trusted = match &peer.trusted_as {
+                Some(name) => format!(", trusted as {}", name),
+                None => String::new(),
+            };
+            println!("{}{}", self.describe_peer(&peer.id), trusted);
+        }
+        Ok(())
+    }
+
+    async fn print_transfers(&self) -> Result<(), NodeError> {
+        let transfers = self.node.transfers().await?;
+        if self.json {
+            Event::Transfers {
+                transfers: transfers_json(&transfers),
+            }
+            .print();
+            return Ok(());
+        }
+        if transfers.is_empty() {
+            println!("No transfers");
+        }
+        for transfer in transfers {
+            let direction = match transfer.direction {
+                Direction::Outgoing => "to",
+                Direction::Incoming => "from",
+            };
+            let peer = match &transfer.peer {
+                Some(peer) => self.describe_peer(peer),
+                None => "any peer".to_string(),
+            };
+            let state = match transfer.state {
+                TransferState::Queued => "queued".to_string(),
+                TransferState::Offered => "waiting for an answer".to_string(),
+                TransferState::Running => {
+                    format!("{}/{} bytes", transfer.bytes_done, transfer.bytes_total)
+                }
+            };
+            println!(
+                "#{} {} {} {}: {}",
+                transfer.id, transfer.name, direction, peer, state
+            );
+        }
+        Ok(())
+    }
+
+    /// The peer named by `to`, or without it the first peer found.
+    async fn target(&self, to: Option<&str>) -> Result<PeerId, NodeError> {
+        match to {
+            Some(name) => Ok(self.node.resolve_peer(name).await?),
+            None => match self.node.peers().await?.into_iter().next() {
+                Some(peer) => Ok(peer.id),
+                None => Err("No peers found".into()),
+            },
         }
     }
+
+    /// Queues `path` for the peer named by `to`, or for the first peer found without it.
+    async fn queue(&self, path: &str, to: Option<&str>) -> Result<(u64, PeerId), NodeError> {
+        let peer = self.target(to).await?;
+        let id = self.node.send_file(peer.clone(), path).await?;
+        Ok((id, peer))
+    }
+
+    /// Queues `path` like `queue` and says so on stdout.
+    async fn send(&self, path: &str, to: Option<&str>) -> Result<u64, NodeError> {
+        let (id, peer) = self.queue(path, to).await?;
+        if self.json {
+            Event::Queued {
+                transfer_id: id,
+                path: path.to_string(),
+                peer: peer.to_base58(),
+            }
+            .print();
+        } else {
+            println!("Transfer {} queued", id);
+        }
+        Ok(id)
+    }
+
+    /// Runs one command typed in interactive mode. Returns false once the user quits.
+    async fn handle_line(&mut self, line: &str) -> Result<bool, NodeError> {
+        let line = line.trim();
+        let mut words = line.splitn(2, ' ');
+        match (
+            words.next().unwrap_or_default(),
+            words.next().map(str::trim),
+        ) {
+            ("", None) => {}
+            ("quit", None) | ("exit", None) => return Ok(false),
+            ("help", None) => print_usage(self.json),
+            ("send", Some(rest)) => {
+                let (path, to) = match rest.rfind(" --to ") {
+                    Some(index) => (&rest[..index], Some(rest[index + 6..].trim())),
+                    None => (rest, None),
+                };
+                self.send(path, to).await?;
+            }
+            ("broadcast", Some(path)) => {
+                let id = self.node.broadcast(path).await?;
+                if self.json {
+                    Event::BroadcastQueued {
+                        broadcast: id,
+                        path: path.to_string(),
+                    }
+                    .print();
+                } else {
+                    println!("Broadcast {} queued", id);
+                }
+            }
+            ("peers", None) => self.print_peers().await?,
+            ("list", None) | ("status", None) => self.print_transfers().await?,
+            ("accept", Some(id)) => self.node.accept(id.parse()?).await?,
+            ("reject", Some(id)) => self.node.reject(id.parse()?).await?,
+            ("cancel", Some(id)) => self.node.cancel(id.parse()?).await?,
+            ("trust", Some(rest)) => {
+                let mut words = rest.splitn(2, ' ');
+                match (words.next(), words.next()) {
+                    (Some(peer), Some(name)) => {
+                        let peer = self.node.resolve_peer(peer).await?;
+                        self.node.trust(peer, name).await?;
+                    }
+                    _ => return Err("Usage: trust <peer> <name>".into()),
+                }
+            }
+            ("untrust", Some(peer)) => {
+                let peer = self.node.resolve_peer(peer).await?;
+                if !self.node.untrust(peer.clone()).await? {
+                    return Err(format!("{} was not trusted", peer.to_base58()).into());
+                }
+            }
+            ("trusted", None) => print_trusted(&self.node.trusted().await?, self.json),
+            (command, _) => {
+                return Err(
+                    format!("Unknown command {:?}, type \"help\" for a list", command).into(),
+                )
+            }
+        }
+        Ok(true)
+    }
+
+    /// Runs a call from the control socket. The calls mirror the commands of `handle_line`,
+    /// but their results go back to the client instead of stdout. Returns `None` for calls
+    /// answered later.
+    async fn handle_call(&mut self, call: &Call) -> Result<Option<Value>, NodeError> {
+        let result = match call.method.as_str() {
+            "send" => {
+                let path = call.str_param("path")?;
+                let wait = call.optional_bool_param("wait")?;
+                let (id, peer) = self.queue(path, call.optional_str_param("to")?).await?;
+                if wait {
+                    // Answered with the event that ends the transfer, see `handle_event`.
+                    self.waiting.insert(id, call.clone());
+                    return Ok(None);
+                }
+                json!({"transfer_id": id, "peer": peer.to_base58()})
+            }
+            "broadcast" => {
+                let id = self.node.broadcast(call.str_param("path")?).await?;
+                json!({ "broadcast": id })
+            }
+            "peers" => output::to_value(&self.peers_json(&self.node.peers().await?)),
+            "transfers" => output::to_value(&transfers_json(&self.node.transfers().await?)),
+            "accept" => {
+                self.node.accept(call.id_param("transfer_id")?).await?;
+                Value::Null
+            }
+            "reject" => {
+                self.node.reject(call.id_param("transfer_id")?).await?;
+                Value::Null
+            }
+            "cancel" => {
+                self.node.cancel(call.id_param("transfer_id")?).await?;
+                Value::Null
+            }
+            "subscribe" => {
+                self.subscribers.push(call.client.clone());
+                Value::Bool(true)
+            }
+            method => return Err(CallError::unknown_method(method).into()),
+        };
+        Ok(Some(result))
+    }
+}
+
+/// Redraws the progress bar of a transfer in place, ending the line once it is complete.
+fn print_progress(transfer_id: u64, bytes_done: u64, bytes_total: u64, rate: u64) {
+    // Without a size there is no bar to fill, only what arrived so far.
+    if bytes_total == 0 {
+        print!(
+            "\r#{} {:.1} KiB {:.1} KiB/s",
+            transfer_id,
+            bytes_done as f64 / 1024.0,
+            rate as f64 / 1024.0
+        );
+        let _ = stdio::stdout().flush();
+        return;
+    }
+    let fraction = bytes_done as f64 / bytes_total as f64;
+    let filled = (fraction * PROGRESS_BAR_WIDTH as f64) as usize;
+    print!(
+        "\r#{} [{}{}] {:>3}% {:.1} KiB/s",
+        transfer_id,
+        "=".repeat(filled),
+        " ".repeat(PROGRESS_BAR_WIDTH - filled),
+        (fraction * 100.0) as u32,
+        rate as f64 / 1024.0
+    );
+    if bytes_done >= bytes_total {
+        println!();
+    }
+    let _ = stdio::stdout().flush();
+}
+
+fn transfers_json(transfers: &[TransferStatus]) -> Vec<output::TransferStatus> {
+    transfers
+        .iter()
+        .map(|transfer| {
+            let direction = match transfer.direction {
+                Direction::Outgoing => "outgoing",
+                Direction::Incoming => "incoming",
+            };
+            let state = match transfer.state {
+                TransferState::Queued => "queued",
+                TransferState::Offered => "offered",
+                TransferState::Running => "running",
+            };
+            output::TransferStatus {
+                transfer_id: transfer.id,
+                name: transfer.name.clone(),
+                direction,
+                peer: transfer.peer.as_ref().map(PeerId::to_base58),
+                state,
+                bytes_done: transfer.bytes_done,
+                bytes_total: transfer.bytes_total,
+            }
+        })
+        .collect()
 }
 
-async fn execute_swarm() {
-    let local_keys = identity::Keypair::generate_ed25519();
+/// Prints the usage, to stderr in `--json` mode where stdout is only JSON lines.
+fn print_usage(json: bool) {
+    if json {
+        eprintln!("{}", USAGE);
+    } else {
+        println!("{}", USAGE);
+    }
+}
+
+fn print_trusted(peers: &[(PeerId, String)], json: bool) {
+    if json {
+        let peers = peers
+            .iter()
+            .map(|(peer, name)| TrustedPeer {
+                peer: peer.to_base58(),
+                name: name.clone(),
+            })
+            .collect();
+        Event::Trusted { peers }.print();
+        return;
+    }
+    for (peer, name) in peers {
+        println!("{} {}", peer.to_base58(), name);
+    }
+}
+
+/// Starts the node. `dial_discovered` connects to every peer found, to learn their names.
+fn start_node(
+    options: &Options,
+    trust_store: TrustStore,
+    dial_discovered: bool,
+) -> Result<Node, Box<dyn Error>> {
+    let local_keys = keypair::load_or_generate(options.identity_path()?)?;
     let local_peer_id = PeerId::from(local_keys.public());
-    println!("I am Peer: {:?}", local_peer_id);
-
-    let mut swarm = {
-        let mdns = Mdns::new().unwrap();
-        let transfer_behaviour = TransferBehaviour::new();
-        let behaviour = MyBehaviour {
-            mdns,
-            transfer_behaviour,
-        };
-        let timeout = Duration::from_secs(60);
-        let transport = TransportTimeout::with_outgoing_timeout(
-            build_development_transport(local_keys.clone()).unwrap(),
-            timeout,
+    if options.json {
+        Event::Started {
+            peer: local_peer_id.to_base58(),
+            download_dir: options.target_path.clone(),
+            hash_algorithm: options.hash_algorithm.to_string(),
+        }
+        .print();
+    } else {
+        println!("I am Peer: {:?}", local_peer_id);
+        println!(
+            "Saving files to {}, hashing with {}",
+            options.target_path, options.hash_algorithm
         );
+    }
 
-        Swarm::new(transport, behaviour, local_peer_id)
-    };
+    let mut builder = NodeBuilder::new(options.target_path.clone())
+        .keypair(local_keys)
+        .listen_on(options.listen_address.parse()?)
+        .hash_algorithm(options.hash_algorithm)
+        .trust_store(trust_store)
+        .refuse_untrusted(options.trusted_only)
+        .dial_discovered(dial_discovered);
+    if let Some(name) = &options.name {
+        builder = builder.name(name.clone());
+    }
+    if let Some(interval) = options.progress_interval {
+        builder = builder.progress_interval(interval);
+    }
+    Ok(builder.spawn().map_err(|e| e.to_string())?)
+}
 
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-
-    Swarm::listen_on(&mut swarm, "/ip4/0.0.0.0/tcp/0".parse().unwrap()).expect("Failed to listen");
-    let mut listening = false;
-    task::block_on(future::poll_fn(move |context: &mut Context| {
-        loop {
-            match stdin.try_poll_next_unpin(context) {
-                Poll::Ready(Some(line)) => match line {
-                    Ok(value) => match swarm.transfer_behaviour.push_payload(value) {
-                        Ok(_) => {}
-                        Err(e) => eprintln!("{:?}", e),
+/// What the running node is for.
+enum Mode {
+    /// Runs until the user quits.
+    Listen,
+    /// Waits for the target peer, sends the file and waits for the result.
+    Send {
+        path: String,
+        to: Option<String>,
+        transfer: Option<u64>,
+    },
+    /// Looks for peers until the deadline.
+    Peers,
+}
+
+/// What woke up `run`.
+enum Input {
+    Event(Option<NodeEvent>),
+    Line(Option<stdio::Result<String>>),
+    Call(Option<Call>),
+    Deadline,
+}
+
+/// Runs the node in the given mode, returning whether it ended successfully.
+async fn run(
+    mut app: App,
+    mut mode: Mode,
+    wait: Duration,
+    control: Option<mpsc::UnboundedReceiver<Call>>,
+) -> bool {
+    let mut events = app.node.events();
+    let mut stdin = match mode {
+        Mode::Listen => io::BufReader::new(io::stdin()).lines().boxed_local(),
+        _ => stream::empty().boxed_local(),
+    }
+    .fuse();
+    let mut calls = match control {
+        Some(calls) => calls.boxed_local(),
+        None => stream::empty().boxed_local(),
+    }
+    .fuse();
+    let mut deadline = Delay::new(wait).fuse();
+    loop {
+        if let Mode::Send {
+            path,
+            to,
+            transfer: transfer @ None,
+        } = &mut mode
+        {
+            // Sends as soon as the target peer is found.
+            if app.target(to.as_deref()).await.is_ok() {
+                match app.send(path, to.as_deref()).await {
+                    Ok(id) => *transfer = Some(id),
+                    Err(e) => {
+                        error!("{}", e);
+                        return false;
+                    }
+                }
+            }
+        }
+
+        let input = select! {
+            event = events.next() => Input::Event(event),
+            line = stdin.next() => Input::Line(line),
+            call = calls.next() => Input::Call(call),
+            _ = deadline => Input::Deadline,
+        };
+        match input {
+            Input::Event(None) => return false,
+            Input::Event(Some(event)) => {
+                let finished = app.handle_event(event);
+                if let (
+                    Mode::Send {
+                        transfer: Some(id), ..
                     },
-                    Err(e) => eprintln!("Line error: {:?}", e),
-                },
-                Poll::Ready(None) => println!("Stdin closed"),
-                Poll::Pending => break,
-            }
-        }
-
-        loop {
-            match swarm.poll_next_unpin(context) {
-                Poll::Ready(Some(event)) => println!("Some event main: {:?}", event),
-                Poll::Ready(None) => return Poll::Ready("aaa"),
-                Poll::Pending => {
-                    if !listening {
-                        for addr in Swarm::listeners(&swarm) {
-                            println!("Listening on {:?}", addr);
-                            listening = true;
-                        }
+                    Some((ended, succeeded)),
+                ) = (&mode, finished)
+                {
+                    if *id == ended {
+                        return succeeded;
                     }
-                    break;
                 }
             }
+            Input::Line(Some(Ok(line))) => match app.handle_line(&line).await {
+                Ok(true) => {}
+                Ok(false) => return true,
+                Err(e) => error!("{}", e),
+            },
+            Input::Line(Some(Err(e))) => error!("Line error: {:?}", e),
+            // Without stdin the node keeps running as a daemon.
+            Input::Line(None) => {}
+            Input::Call(Some(call)) => {
+                if let Some(result) = app.handle_call(&call).await.transpose() {
+                    call.answer(result);
+                }
+            }
+            Input::Call(None) => {}
+            Input::Deadline => match mode {
+                Mode::Peers => {
+                    if let Err(e) = app.print_peers().await {
+                        error!("{}", e);
+                        return false;
+                    }
+                    return true;
+                }
+                Mode::Send { transfer: None, .. } => {
+                    error!("No peer to send to was found");
+                    return false;
+                }
+                Mode::Listen | Mode::Send { .. } => {}
+            },
         }
-        Poll::Pending
-    }));
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let future = execute_swarm();
-    executor::block_on(future);
+    let options = cli::parse_options(env::args().skip(1))?;
+    // An explicit filter wins over -v and -q.
+    let filter = options
+        .log_filter
+        .clone()
+        .or_else(|| env::var(logger::LOG_ENV).ok())
+        .unwrap_or_else(|| logger::verbosity_filter(options.verbosity));
+    logger::init(&filter)?;
+    let mode = match &options.command {
+        Command::Help => {
+            print_usage(options.json);
+            return Ok(());
+        }
+        Command::Trust(command) => {
+            let trust_path = options.trust_path()?;
+            let mut trust_store = TrustStore::load(trust_path)?;
+            match command {
+                TrustCommand::Add { peer, name } => {
+                    let peer = peer.parse::<PeerId>().map_err(|_| "Invalid peer id")?;
+                    trust_store.add(peer, name.clone())?;
+                }
+                TrustCommand::Remove { peer } => {
+                    let peer = peer.parse::<PeerId>().map_err(|_| "Invalid peer id")?;
+                    if !trust_store.remove(&peer)? {
+                        return Err(format!("{} was not trusted", peer.to_base58()).into());
+                    }
+                }
+                TrustCommand::List => {
+                    let peers: Vec<(PeerId, String)> = trust_store
+                        .peers()
+                        .into_iter()
+                        .map(|(peer, name)| (peer.clone(), name.to_string()))
+                        .collect();
+                    print_trusted(&peers, options.json)
+                }
+            }
+            return Ok(());
+        }
+        Command::Identity { rotate } => {
+            let identity_path = options.identity_path()?;
+            let local_keys = if *rotate {
+                keypair::generate(identity_path)?
+            } else {
+                keypair::load_or_generate(identity_path)?
+            };
+            let peer = PeerId::from(local_keys.public());
+            if options.json {
+                Event::Identity {
+                    peer: peer.to_base58(),
+                    path: identity_path.display().to_string(),
+                }
+                .print();
+            } else {
+                println!("{} ({})", peer.to_base58(), identity_path.display());
+            }
+            return Ok(());
+        }
+        Command::Status => {
+            let (identity_path, trust_path) = (options.identity_path()?, options.trust_path()?);
+            let local_keys = keypair::load_or_generate(identity_path)?;
+            let trust_store = TrustStore::load(trust_path)?;
+            if options.json {
+                let info = PeerInfo::local(options.name.clone());
+                Event::Status {
+                    peer: PeerId::from(local_keys.public()).to_base58(),
+                    name: info.name,
+                    host: info.hostname,
+                    identity: identity_path.display().to_string(),
+                    download_dir: options.target_path.clone(),
+                    listen_address: options.listen_address.clone(),
+                    hash_algorithm: options.hash_algorithm.to_string(),
+                    trusted_peers: trust_store.peers().len() as u64,
+                    trusted: trust_path.display().to_string(),
+                    trusted_only: options.trusted_only,
+                }
+                .print();
+                return Ok(());
+            }
+            println!("Peer id: {}", PeerId::from(local_keys.public()).to_base58());
+            println!("Announced as: {}", PeerInfo::local(options.name.clone()));
+            println!("Identity: {}", identity_path.display());
+            println!("Download directory: {}", options.target_path);
+            println!("Listen address: {}", options.listen_address);
+            println!("Hash algorithm: {}", options.hash_algorithm);
+            println!(
+                "Trusted peers: {} in {}{}",
+                trust_store.peers().len(),
+                trust_path.display(),
+                if options.trusted_only {
+                    ", others are refused"
+                } else {
+                    ""
+                }
+            );
+            return Ok(());
+        }
+        Command::Listen => Mode::Listen,
+        Command::Send { path, to } => Mode::Send {
+            path: path.clone(),
+            to: to.clone(),
+            transfer: None,
+        },
+        Command::Peers => Mode::Peers,
+    };
+
+    let trust_store = TrustStore::load(options.trust_path()?)?;
+    let control = match (&mode, &options.control_path) {
+        (Mode::Listen, Some(path)) => Some(control::listen(path)?),
+        _ => None,
+    };
+    let app = App {
+        // Listing peers is pointless without their names.
+        node: start_node(&options, trust_store, matches!(mode, Mode::Peers))?,
+        json: options.json,
+        peer_info: HashMap::new(),
+        subscribers: vec![],
+        waiting: HashMap::new(),
+    };
+    if !task::block_on(run(app, mode, options.wait, control)) {
+        process::exit(1);
+    }
     Ok(())
 }
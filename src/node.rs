@@ -0,0 +1,748 @@
+use std::error::Error;
+use std::path::Path;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_std::task;
+use futures::channel::{mpsc, oneshot};
+use futures::{future, prelude::*};
+use libp2p::core::transport::timeout::TransportTimeout;
+use libp2p::core::{identity::Keypair, Multiaddr, PeerId};
+use libp2p::identify::{Identify, IdentifyEvent};
+use libp2p::mdns::{Mdns, MdnsEvent};
+use libp2p::swarm::toggle::Toggle;
+use libp2p::swarm::NetworkBehaviourEventProcess;
+use libp2p::{build_development_transport, NetworkBehaviour, Swarm};
+use tracing::debug;
+
+use crate::behaviour::{TransferBehaviour, TransferStatus};
+use crate::contents::{ReceiveSink, SendSource};
+use crate::hash::HashAlgorithm;
+use crate::peer_info::{PeerInfo, IDENTIFY_PROTOCOL};
+use crate::protocol::ProtocolEvent;
+use crate::trust::TrustStore;
+
+/// Errors of `Node` calls. They are sent from the task driving the swarm, so they have
+/// to be `Send`.
+pub type NodeError = Box<dyn Error + Send + Sync>;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "/ip4/0.0.0.0/tcp/0";
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a `Node` reports, see `Node::events`.
+#[derive(Clone, Debug)]
+pub enum NodeEvent {
+    /// The node accepts connections on this address.
+    Listening(Multiaddr),
+    /// mDNS found a peer on the local network.
+    Discovered(PeerId),
+    /// A peer announced its name, or changed it.
+    Identified { peer: PeerId, info: PeerInfo },
+    /// mDNS lost track of a peer.
+    Expired(PeerId),
+    /// Connections, offers, progress and results of transfers.
+    Transfer(ProtocolEvent),
+}
+
+/// A peer the node knows about, see `Node::peers`.
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub id: PeerId,
+    /// Missing until the peer identified itself.
+    pub info: Option<PeerInfo>,
+    pub connected: bool,
+    /// Name of the peer in the trust store.
+    pub trusted_as: Option<String>,
+}
+
+/// What happens to files other peers want to send.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OfferPolicy {
+    /// Offers are reported as `ProtocolEvent::IncomingOffer` and wait for `Node::accept`
+    /// or `Node::reject`. Offers from trusted peers are accepted right away.
+    Ask,
+    /// Every offer is accepted.
+    AcceptAll,
+    /// Offers from trusted peers are accepted, all others rejected.
+    TrustedOnly,
+}
+
+/// Configures and starts a `Node`.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), p2pshare::node::NodeError> {
+/// use futures::prelude::*;
+/// use p2pshare::node::{NodeBuilder, NodeEvent, OfferPolicy};
+///
+/// let node = NodeBuilder::new("/tmp/files")
+///     .offer_policy(OfferPolicy::AcceptAll)
+///     .spawn()?;
+/// let mut events = node.events();
+/// while let Some(event) = events.next().await {
+///     if let NodeEvent::Identified { peer, .. } = event {
+///         node.send_file(peer, "notes.txt").await?;
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct NodeBuilder {
+    keypair: Option<Keypair>,
+    listen_addresses: Vec<Multiaddr>,
+    download_dir: String,
+    name: Option<String>,
+    hash_algorithm: HashAlgorithm,
+    progress_interval: Option<Duration>,
+    trust_store: TrustStore,
+    refuse_untrusted: bool,
+    offer_policy: OfferPolicy,
+    receive_sink: ReceiveSink,
+    dial_discovered: bool,
+    mdns: bool,
+}
+
+impl NodeBuilder {
+    /// Received files are written to `download_dir`.
+    pub fn new(download_dir: impl Into<String>) -> NodeBuilder {
+        NodeBuilder {
+            keypair: None,
+            listen_addresses: vec![],
+            download_dir: download_dir.into(),
+            name: None,
+            hash_algorithm: HashAlgorithm::default(),
+            progress_interval: None,
+            trust_store: TrustStore::default(),
+            refuse_untrusted: false,
+            offer_policy: OfferPolicy::Ask,
+            receive_sink: ReceiveSink::default(),
+            dial_discovered: false,
+            mdns: true,
+        }
+    }
+
+    /// The identity of the node. Without it a new one is generated, see
+    /// `keypair::load_or_generate` to keep it across runs.
+    pub fn keypair(mut self, keypair: Keypair) -> NodeBuilder {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Adds an address to listen on. Without any, the node listens on a random TCP port
+    /// of every interface.
+    pub fn listen_on(mut self, address: Multiaddr) -> NodeBuilder {
+        self.listen_addresses.push(address);
+        self
+    }
+
+    /// Name announced to other peers, `$USER` by default.
+    pub fn name(mut self, name: impl Into<String>) -> NodeBuilder {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> NodeBuilder {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// See `TransferBehaviour::set_progress_interval`.
+    pub fn progress_interval(mut self, interval: Duration) -> NodeBuilder {
+        self.progress_interval = Some(interval);
+        self
+    }
+
+    /// See `TransferBehaviour::set_trust_store`.
+    pub fn trust_store(mut self, store: TrustStore) -> NodeBuilder {
+        self.trust_store = store;
+        self
+    }
+
+    /// See `TransferBehaviour::set_refuse_untrusted`.
+    pub fn refuse_untrusted(mut self, refuse: bool) -> NodeBuilder {
+        self.refuse_untrusted = refuse;
+        self
+    }
+
+    pub fn offer_policy(mut self, policy: OfferPolicy) -> NodeBuilder {
+        self.offer_policy = policy;
+        self
+    }
+
+    /// See `TransferBehaviour::set_receive_sink`.
+    pub fn receive_sink(mut self, sink: ReceiveSink) -> NodeBuilder {
+        self.receive_sink = sink;
+        self
+    }
+
+    /// See `TransferBehaviour::set_dial_discovered`.
+    pub fn dial_discovered(mut self, dial: bool) -> NodeBuilder {
+        self.dial_discovered = dial;
+        self
+    }
+
+    /// Whether peers on the local network are found with mDNS, on by default. Without it,
+    /// peers are only reached with `Node::dial` or when they connect to us.
+    pub fn mdns(mut self, enabled: bool) -> NodeBuilder {
+        self.mdns = enabled;
+        self
+    }
+
+    /// Starts listening and runs the node on its own task until every handle is dropped.
+    pub fn spawn(self) -> Result<Node, NodeError> {
+        let keypair = self.keypair.unwrap_or_else(Keypair::generate_ed25519);
+        let local_peer_id = PeerId::from(keypair.public());
+
+        let identify = Identify::new(
+            IDENTIFY_PROTOCOL.to_string(),
+            PeerInfo::local(self.name).to_agent_version(),
+            keypair.public(),
+        );
+        let mut transfer_behaviour = TransferBehaviour::new(self.download_dir);
+        transfer_behaviour.set_hash_algorithm(self.hash_algorithm);
+        if let Some(interval) = self.progress_interval {
+            transfer_behaviour.set_progress_interval(interval);
+        }
+        transfer_behaviour.set_trust_store(self.trust_store);
+        transfer_behaviour.set_refuse_untrusted(self.refuse_untrusted);
+        transfer_behaviour.set_receive_sink(self.receive_sink);
+        transfer_behaviour.set_dial_discovered(self.dial_discovered);
+        let mdns = if self.mdns { Some(Mdns::new()?) } else { None };
+        let behaviour = NodeBehaviour {
+            mdns: Toggle::from(mdns),
+            identify,
+            transfer_behaviour,
+            offer_policy: self.offer_policy,
+            subscribers: vec![],
+            listening: vec![],
+        };
+        let transport = TransportTimeout::with_outgoing_timeout(
+            build_development_transport(keypair)?,
+            CONNECTION_TIMEOUT,
+        );
+        let mut swarm = Swarm::new(transport, behaviour, local_peer_id.clone());
+
+        let mut listen_addresses = self.listen_addresses;
+        if listen_addresses.is_empty() {
+            listen_addresses.push(DEFAULT_LISTEN_ADDRESS.parse()?);
+        }
+        for address in listen_addresses {
+            Swarm::listen_on(&mut swarm, address)?;
+        }
+
+        let (requests, receiver) = mpsc::unbounded();
+        task::spawn(run(swarm, receiver));
+        Ok(Node {
+            local_peer_id,
+            requests,
+        })
+    }
+}
+
+/// A handle to a running node. Clones drive the same node, which stops once all of them
+/// are dropped.
+#[derive(Clone)]
+pub struct Node {
+    local_peer_id: PeerId,
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl Node {
+    pub fn local_peer_id(&self) -> &PeerId {
+        &self.local_peer_id
+    }
+
+    /// Everything that happens from now on, after a `Listening` event for every address
+    /// the node already listens on. Every call returns its own stream.
+    pub fn events(&self) -> mpsc::UnboundedReceiver<NodeEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        // A stopped node drops the sender, which ends the stream.
+        let _ = self.requests.unbounded_send(Request::Subscribe(sender));
+        receiver
+    }
+
+    /// Queues the file or directory at `path` for `peer`, returning the transfer id used
+    /// by its events.
+    pub async fn send_file(&self, peer: PeerId, path: impl AsRef<Path>) -> Result<u64, NodeError> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        self.call(|reply| Request::Send { peer, path, reply })
+            .await?
+    }
+
+    /// Queues contents generated on the fly, see `SendSource`, for `peer` under `name`.
+    pub async fn send(
+        &self,
+        peer: PeerId,
+        name: impl Into<String>,
+        source: SendSource,
+    ) -> Result<u64, NodeError> {
+        let name = name.into();
+        self.call(|reply| Request::SendSource {
+            peer,
+            name,
+            source,
+            reply,
+        })
+        .await?
+    }
+
+    /// Sends the file at `path` to every discovered peer, connected or not, see
+    /// `TransferBehaviour::push_broadcast`.
+    pub async fn broadcast(&self, path: impl AsRef<Path>) -> Result<u64, NodeError> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        self.call(|reply| Request::Broadcast { path, reply })
+            .await?
+    }
+
+    /// Finds a discovered peer by the name it announced, its host name or its name in the
+    /// trust store, or parses a peer id. When none matches, peers that did not announce a
+    /// name yet are dialed, see `TransferBehaviour::lookup_peer`.
+    pub async fn resolve_peer(&self, name: impl Into<String>) -> Result<PeerId, NodeError> {
+        let name = name.into();
+        self.call(|reply| Request::Resolve { name, reply }).await?
+    }
+
+    /// Connects to the peer listening on `address`, see `TransferBehaviour::dial_address`.
+    pub async fn dial(&self, address: Multiaddr) -> Result<(), NodeError> {
+        self.call(|reply| Request::Dial { address, reply }).await
+    }
+
+    /// Peers found on the network or connected, sorted by peer id.
+    pub async fn peers(&self) -> Result<Vec<Peer>, NodeError> {
+        self.call(Request::Peers).await
+    }
+
+    /// Transfers that have not finished yet.
+    pub async fn transfers(&self) -> Result<Vec<TransferStatus>, NodeError> {
+        self.call(Request::Transfers).await
+    }
+
+    pub async fn accept(&self, transfer_id: u64) -> Result<(), NodeError> {
+        self.call(|reply| Request::Accept { transfer_id, reply })
+            .await?
+    }
+
+    pub async fn reject(&self, transfer_id: u64) -> Result<(), NodeError> {
+        self.call(|reply| Request::Reject { transfer_id, reply })
+            .await?
+    }
+
+    pub async fn cancel(&self, transfer_id: u64) -> Result<(), NodeError> {
+        self.call(|reply| Request::Cancel { transfer_id, reply })
+            .await?
+    }
+
+    /// Trusts `peer` under `name`, saving the trust store, see `TrustStore::add`.
+    pub async fn trust(&self, peer: PeerId, name: impl Into<String>) -> Result<(), NodeError> {
+        let name = name.into();
+        self.call(|reply| Request::Trust { peer, name, reply })
+            .await?
+    }
+
+    /// Returns whether the peer was trusted.
+    pub async fn untrust(&self, peer: PeerId) -> Result<bool, NodeError> {
+        self.call(|reply| Request::Untrust { peer, reply }).await?
+    }
+
+    /// Trusted peers and their names, sorted by name.
+    pub async fn trusted(&self) -> Result<Vec<(PeerId, String)>, NodeError> {
+        self.call(Request::Trusted).await
+    }
+
+    async fn call<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<T>) -> Request,
+    ) -> Result<T, NodeError> {
+        let (reply, answer) = oneshot::channel();
+        self.requests
+            .unbounded_send(request(reply))
+            .map_err(|_| "The node has stopped")?;
+        Ok(answer.await.map_err(|_| "The node has stopped")?)
+    }
+}
+
+/// Calls from `Node` handles, run by the task that owns the swarm.
+enum Request {
+    Send {
+        peer: PeerId,
+        path: String,
+        reply: oneshot::Sender<Result<u64, NodeError>>,
+    },
+    SendSource {
+        peer: PeerId,
+        name: String,
+        source: SendSource,
+        reply: oneshot::Sender<Result<u64, NodeError>>,
+    },
+    Broadcast {
+        path: String,
+        reply: oneshot::Sender<Result<u64, NodeError>>,
+    },
+    Resolve {
+        name: String,
+        reply: oneshot::Sender<Result<PeerId, NodeError>>,
+    },
+    Dial {
+        address: Multiaddr,
+        reply: oneshot::Sender<()>,
+    },
+    Peers(oneshot::Sender<Vec<Peer>>),
+    Transfers(oneshot::Sender<Vec<TransferStatus>>),
+    Accept {
+        transfer_id: u64,
+        reply: oneshot::Sender<Result<(), NodeError>>,
+    },
+    Reject {
+        transfer_id: u64,
+        reply: oneshot::Sender<Result<(), NodeError>>,
+    },
+    Cancel {
+        transfer_id: u64,
+        reply: oneshot::Sender<Result<(), NodeError>>,
+    },
+    Trust {
+        peer: PeerId,
+        name: String,
+        reply: oneshot::Sender<Result<(), NodeError>>,
+    },
+    Untrust {
+        peer: PeerId,
+        reply: oneshot::Sender<Result<bool, NodeError>>,
+    },
+    Trusted(oneshot::Sender<Vec<(PeerId, String)>>),
+    Subscribe(mpsc::UnboundedSender<NodeEvent>),
+}
+
+#[derive(NetworkBehaviour)]
+struct NodeBehaviour {
+    mdns: Toggle<Mdns>,
+    identify: Identify,
+    transfer_behaviour: TransferBehaviour,
+    #[behaviour(ignore)]
+    offer_policy: OfferPolicy,
+    #[behaviour(ignore)]
+    subscribers: Vec<mpsc::UnboundedSender<NodeEvent>>,
+    /// Addresses already announced with `NodeEvent::Listening`.
+    #[behaviour(ignore)]
+    listening: Vec<Multiaddr>,
+}
+
+impl NodeBehaviour {
+    fn publish(&mut self, event: NodeEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    fn handle(&mut self, request: Request) {
+        // Errors of the behaviour are not `Send`, only their message is passed on.
+        fn send_error<T>(result: Result<T, Box<dyn Error>>) -> Result<T, NodeError> {
+            result.map_err(|e| e.to_string().into())
+        }
+        let transfer_behaviour = &mut self.transfer_behaviour;
+        match request {
+            Request::Send { peer, path, reply } => {
+                let _ = reply.send(send_error(
+                    transfer_behaviour.push_payload(path, Some(peer)),
+                ));
+            }
+            Request::SendSource {
+                peer,
+                name,
+                source,
+                reply,
+            } => {
+                let _ = reply.send(send_error(transfer_behaviour.push_source(
+                    name,
+                    source,
+                    Some(peer),
+                )));
+            }
+            Request::Broadcast { path, reply } => {
+                let _ = reply.send(send_error(transfer_behaviour.push_broadcast(path)));
+            }
+            Request::Resolve { name, reply } => {
+                let _ = reply.send(send_error(transfer_behaviour.lookup_peer(&name)));
+            }
+            Request::Dial { address, reply } => {
+                transfer_behaviour.dial_address(address);
+                let _ = reply.send(());
+            }
+            Request::Peers(reply) => {
+                let mut peers: Vec<Peer> = transfer_behaviour
+                    .peers
+                    .union(&transfer_behaviour.connected_peers)
+                    .map(|peer| Peer {
+                        id: peer.clone(),
+                        info: transfer_behaviour.peer_info(peer).cloned(),
+                        connected: transfer_behaviour.connected_peers.contains(peer),
+                        trusted_as: transfer_behaviour
+                            .trust_store()
+                            .name(peer)
+                            .map(str::to_string),
+                    })
+                    .collect();
+                peers.sort_by_key(|peer| peer.id.to_base58());
+                let _ = reply.send(peers);
+            }
+            Request::Transfers(reply) => {
+                let _ = reply.send(transfer_behaviour.transfers());
+            }
+            Request::Accept { transfer_id, reply } => {
+                let _ = reply.send(send_error(transfer_behaviour.accept(transfer_id)));
+            }
+            Request::Reject { transfer_id, reply } => {
+                let _ = reply.send(send_error(transfer_behaviour.reject(transfer_id)));
+            }
+            Request::Cancel { transfer_id, reply } => {
+                let _ = reply.send(send_error(transfer_behaviour.cancel(transfer_id)));
+            }
+            Request::Trust { peer, name, reply } => {
+                let result = transfer_behaviour.trust_store_mut().add(peer, name);
+                let _ = reply.send(result.map_err(NodeError::from));
+            }
+            Request::Untrust { peer, reply } => {
+                let result = transfer_behaviour.trust_store_mut().remove(&peer);
+                let _ = reply.send(result.map_err(NodeError::from));
+            }
+            Request::Trusted(reply) => {
+                let trusted = transfer_behaviour
+                    .trust_store()
+                    .peers()
+                    .into_iter()
+                    .map(|(peer, name)| (peer.clone(), name.to_string()))
+                    .collect();
+                let _ = reply.send(trusted);
+            }
+            Request::Subscribe(subscriber) => {
+                for address in &self.listening {
+                    let _ = subscriber.unbounded_send(NodeEvent::Listening(address.clone()));
+                }
+                self.subscribers.push(subscriber);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for NodeBehaviour {
+    fn inject_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(list) => {
+                for (peer, _addr) in list {
+                    if !self.transfer_behaviour.peers.contains(&peer) {
+                        self.publish(NodeEvent::Discovered(peer.clone()));
+                    }
+                    self.transfer_behaviour.add_peer(peer);
+                }
+            }
+            MdnsEvent::Expired(list) => {
+                for (peer, _addr) in list {
+                    if self.transfer_behaviour.peers.remove(&peer) {
+                        self.publish(NodeEvent::Expired(peer));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for NodeBehaviour {
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received { peer_id, info, .. } = event {
+            if let Some(info) = PeerInfo::from_identify(&info) {
+                if self.transfer_behaviour.peer_info(&peer_id) != Some(&info) {
+                    self.publish(NodeEvent::Identified {
+                        peer: peer_id.clone(),
+                        info: info.clone(),
+                    });
+                }
+                self.transfer_behaviour.set_peer_info(peer_id, info);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<ProtocolEvent> for NodeBehaviour {
+    fn inject_event(&mut self, event: ProtocolEvent) {
+        if let ProtocolEvent::IncomingOffer { id, .. } = &event {
+            let answer = match self.offer_policy {
+                OfferPolicy::Ask => Ok(()),
+                OfferPolicy::AcceptAll => self.transfer_behaviour.accept(*id),
+                OfferPolicy::TrustedOnly => self.transfer_behaviour.reject(*id),
+            };
+            if let Err(e) = answer {
+                debug!("Cannot answer offer {}: {}", id, e);
+            }
+        }
+        self.publish(NodeEvent::Transfer(event));
+    }
+}
+
+/// Drives the swarm and answers the requests of the handles until they are all gone.
+async fn run(mut swarm: Swarm<NodeBehaviour>, mut requests: mpsc::UnboundedReceiver<Request>) {
+    future::poll_fn(move |context: &mut Context| {
+        loop {
+            match requests.poll_next_unpin(context) {
+                Poll::Ready(Some(request)) => swarm.handle(request),
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match swarm.poll_next_unpin(context) {
+                Poll::Ready(Some(event)) => debug!("Swarm event: {:?}", event),
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+
+        let new: Vec<Multiaddr> = Swarm::listeners(&swarm)
+            .filter(|address| !swarm.listening.contains(*address))
+            .cloned()
+            .collect();
+        for address in new {
+            swarm.listening.push(address.clone());
+            swarm.publish(NodeEvent::Listening(address));
+        }
+        Poll::Pending
+    })
+    .await;
+    debug!("Node stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use std::sync::Arc;
+
+    use crate::error::TransferError;
+
+    fn node() -> Node {
+        NodeBuilder::new("/tmp/files")
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .mdns(false)
+            .receive_sink(ReceiveSink::memory(1 << 20))
+            .spawn()
+            .unwrap()
+    }
+
+    /// Waits for the first event `select` picks something from.
+    async fn next<T>(
+        events: &mut mpsc::UnboundedReceiver<NodeEvent>,
+        select: impl Fn(NodeEvent) -> Option<T>,
+    ) -> T {
+        let find = async {
+            while let Some(event) = events.next().await {
+                if let Some(found) = select(event) {
+                    return found;
+                }
+            }
+            panic!("The node stopped");
+        };
+        timeout(Duration::from_secs(10), find)
+            .await
+            .expect("Timed out waiting for an event")
+    }
+
+    /// Dials `receiver` from `sender` and waits until they are connected.
+    async fn connect(
+        sender: &Node,
+        sender_events: &mut mpsc::UnboundedReceiver<NodeEvent>,
+        receiver: &Node,
+    ) {
+        let mut receiver_events = receiver.events();
+        let address = next(&mut receiver_events, |event| match event {
+            NodeEvent::Listening(address) => Some(address),
+            _ => None,
+        })
+        .await;
+        sender.dial(address).await.unwrap();
+        let peer = receiver.local_peer_id().clone();
+        next(sender_events, |event| match event {
+            NodeEvent::Transfer(ProtocolEvent::Connected { peer: connected })
+                if connected == peer =>
+            {
+                Some(())
+            }
+            _ => None,
+        })
+        .await;
+    }
+
+    #[test]
+    fn test_offer_accept_receive() {
+        let contents = b"contents sent between two nodes".to_vec();
+        let (sender, receiver) = (node(), node());
+        task::block_on(async {
+            let mut sender_events = sender.events();
+            let mut receiver_events = receiver.events();
+            connect(&sender, &mut sender_events, &receiver).await;
+
+            let source = SendSource::bytes(contents.clone());
+            let peer = receiver.local_peer_id().clone();
+            let transfer_id = sender.send(peer, "notes.txt", source).await.unwrap();
+
+            let (id, from, size_bytes) = next(&mut receiver_events, |event| match event {
+                NodeEvent::Transfer(ProtocolEvent::IncomingOffer {
+                    id,
+                    peer,
+                    name,
+                    size_bytes,
+                    ..
+                }) if name == "notes.txt" => Some((id, peer, size_bytes)),
+                _ => None,
+            })
+            .await;
+            assert_eq!(&from, sender.local_peer_id());
+            assert_eq!(size_bytes, contents.len());
+            assert_eq!(receiver.transfers().await.unwrap().len(), 1);
+            receiver.accept(id).await.unwrap();
+
+            let received = next(&mut receiver_events, |event| match event {
+                NodeEvent::Transfer(ProtocolEvent::Received {
+                    verified, contents, ..
+                }) => Some((verified, contents)),
+                _ => None,
+            })
+            .await;
+            assert_eq!(received, (true, Some(Arc::new(contents.clone()))));
+            let sent = next(&mut sender_events, |event| match event {
+                NodeEvent::Transfer(ProtocolEvent::Sent { transfer_id, .. }) => Some(transfer_id),
+                _ => None,
+            })
+            .await;
+            assert_eq!(sent, transfer_id);
+            assert!(receiver.transfers().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_rejected_offer_fails_on_the_sender() {
+        let (sender, receiver) = (node(), node());
+        task::block_on(async {
+            let mut sender_events = sender.events();
+            let mut receiver_events = receiver.events();
+            connect(&sender, &mut sender_events, &receiver).await;
+
+            let source = SendSource::bytes(b"unwanted".to_vec());
+            let peer = receiver.local_peer_id().clone();
+            let transfer_id = sender.send(peer, "spam.txt", source).await.unwrap();
+            let id = next(&mut receiver_events, |event| match event {
+                NodeEvent::Transfer(ProtocolEvent::IncomingOffer { id, .. }) => Some(id),
+                _ => None,
+            })
+            .await;
+            receiver.reject(id).await.unwrap();
+
+            let (failed, error) = next(&mut sender_events, |event| match event {
+                NodeEvent::Transfer(ProtocolEvent::Failed {
+                    transfer_id, error, ..
+                }) => Some((transfer_id, error)),
+                _ => None,
+            })
+            .await;
+            assert_eq!(failed, transfer_id);
+            assert!(matches!(error, TransferError::Rejected));
+        });
+    }
+}
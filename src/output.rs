@@ -0,0 +1,210 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// One line of `--json` output: an object whose `type` field says what happened.
+///
+/// Peers are written by their base58 id, everything else that is not a number or a
+/// flag as a string. Missing optional values are written as `null`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Started {
+        peer: String,
+        download_dir: String,
+        hash_algorithm: String,
+    },
+    Listening {
+        address: String,
+    },
+    Discovered(PeerFields),
+    Identified(PeerFields),
+    Expired(PeerFields),
+    Connected(PeerFields),
+    Disconnected(PeerFields),
+    Offer {
+        #[serde(flatten)]
+        peer: PeerFields,
+        transfer_id: u64,
+        name: String,
+        hash: String,
+        size_bytes: u64,
+    },
+    Received {
+        transfer_id: u64,
+        name: String,
+        path: String,
+        hash: String,
+        hash_algorithm: String,
+        size_bytes: u64,
+        verified: bool,
+    },
+    InvalidName {
+        transfer_id: u64,
+        name: String,
+        error: String,
+    },
+    Declined {
+        transfer_id: u64,
+        name: String,
+    },
+    Sent {
+        transfer_id: u64,
+        name: String,
+        broadcast: Option<u64>,
+    },
+    Failed {
+        transfer_id: u64,
+        name: Option<String>,
+        broadcast: Option<u64>,
+        error: String,
+    },
+    Cancelled {
+        transfer_id: u64,
+        name: String,
+        broadcast: Option<u64>,
+    },
+    Progress {
+        transfer_id: u64,
+        bytes_done: u64,
+        bytes_total: u64,
+        rate: u64,
+    },
+    BroadcastProgress {
+        #[serde(flatten)]
+        peer: PeerFields,
+        broadcast: u64,
+        ok: bool,
+        error: Option<String>,
+        done: u64,
+        total: u64,
+    },
+    BroadcastFinished {
+        broadcast: u64,
+        name: String,
+        sent: Vec<String>,
+        failed: Vec<String>,
+    },
+    Queued {
+        transfer_id: u64,
+        path: String,
+        peer: String,
+    },
+    BroadcastQueued {
+        broadcast: u64,
+        path: String,
+    },
+    Peers {
+        peers: Vec<PeerStatus>,
+    },
+    Transfers {
+        transfers: Vec<TransferStatus>,
+    },
+    Trusted {
+        peers: Vec<TrustedPeer>,
+    },
+    Identity {
+        peer: String,
+        path: String,
+    },
+    Status {
+        peer: String,
+        name: String,
+        host: String,
+        identity: String,
+        download_dir: String,
+        listen_address: String,
+        hash_algorithm: String,
+        trusted_peers: u64,
+        trusted: String,
+        trusted_only: bool,
+    },
+}
+
+impl Event {
+    /// Prints the event as one line on stdout.
+    pub fn print(&self) {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = writeln!(stdout, "{}", self.to_json());
+        let _ = stdout.flush();
+    }
+
+    pub fn to_json(&self) -> String {
+        // Only strings, numbers and flags, which always serialize.
+        serde_json::to_string(self).expect("Events serialize")
+    }
+}
+
+/// `value` as JSON, for the results of control calls.
+pub fn to_value(value: &impl Serialize) -> Value {
+    // Like `Event::to_json`, the output types always serialize.
+    serde_json::to_value(value).expect("Output serializes")
+}
+
+/// The id of a peer and, once it identified itself, its name and host name.
+#[derive(Serialize)]
+pub struct PeerFields {
+    pub peer: String,
+    pub peer_name: Option<String>,
+    pub peer_host: Option<String>,
+}
+
+/// A peer as listed by `peers`.
+#[derive(Serialize)]
+pub struct PeerStatus {
+    #[serde(flatten)]
+    pub peer: PeerFields,
+    pub connected: bool,
+    pub trusted_as: Option<String>,
+}
+
+/// A transfer as listed by `transfers`.
+#[derive(Serialize)]
+pub struct TransferStatus {
+    pub transfer_id: u64,
+    pub name: String,
+    /// `outgoing` or `incoming`.
+    pub direction: &'static str,
+    pub peer: Option<String>,
+    /// `queued`, `offered` or `running`.
+    pub state: &'static str,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+/// A peer as listed by `trusted`.
+#[derive(Serialize)]
+pub struct TrustedPeer {
+    pub peer: String,
+    pub name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_are_tagged() {
+        let event = Event::Sent {
+            transfer_id: 3,
+            name: "a \"b\"".to_string(),
+            broadcast: None,
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"type":"sent","transfer_id":3,"name":"a \"b\"","broadcast":null}"#
+        );
+
+        let peer = PeerFields {
+            peer: "Qm".to_string(),
+            peer_name: Some("bob".to_string()),
+            peer_host: None,
+        };
+        assert_eq!(
+            Event::Connected(peer).to_json(),
+            r#"{"type":"connected","peer":"Qm","peer_name":"bob","peer_host":null}"#
+        );
+    }
+}
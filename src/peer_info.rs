@@ -0,0 +1,69 @@
+use libp2p::identify::IdentifyInfo;
+use std::{env, fmt};
+
+/// Protocol version announced through libp2p identify, peers announcing anything else
+/// are not p2pshare nodes.
+pub const IDENTIFY_PROTOCOL: &str = "/p2pshare/1.0.0";
+
+/// How a node introduces itself to the peers it connects to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerInfo {
+    pub name: String,
+    pub hostname: String,
+}
+
+impl PeerInfo {
+    /// Describes this machine, named `name` or, without it, after the current user.
+    pub fn local(name: Option<String>) -> PeerInfo {
+        let hostname = hostname().unwrap_or_else(|| "unknown".to_string());
+        let name = name
+            .or_else(|| env::var("USER").ok())
+            .unwrap_or_else(|| hostname.clone());
+        PeerInfo { name, hostname }
+    }
+
+    /// Agent version announced through identify, `<name>@<hostname>`.
+    pub fn to_agent_version(&self) -> String {
+        format!("{}@{}", self.name, self.hostname)
+    }
+
+    /// Reads what a remote peer announced, if it is a p2pshare node.
+    pub fn from_identify(info: &IdentifyInfo) -> Option<PeerInfo> {
+        if info.protocol_version != IDENTIFY_PROTOCOL {
+            return None;
+        }
+        // Host names cannot contain `@`, names can.
+        let mut parts = info.agent_version.rsplitn(2, '@');
+        let hostname = parts.next()?.to_string();
+        let name = parts.next()?.to_string();
+        Some(PeerInfo { name, hostname })
+    }
+
+    /// Whether the user means this peer by `name`, either its name or its host name.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.hostname.eq_ignore_ascii_case(name)
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.hostname)
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buff = [0u8; 256];
+    // Safe: the buffer outlives the call and its length is passed along.
+    let result = unsafe { libc::gethostname(buff.as_mut_ptr() as *mut libc::c_char, buff.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buff.iter().position(|b| *b == 0).unwrap_or(buff.len());
+    String::from_utf8(buff[..len].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
@@ -1,73 +1,319 @@
+use async_std::fs as asyncfs;
 use async_std::fs::File as AsyncFile;
 use async_std::io as asyncio;
-use crypto::digest::Digest;
-use crypto::sha1::Sha1;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either};
 use futures::prelude::*;
-use libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
-use std::fs::File;
+use futures_timer::Delay;
+use libp2p::core::{InboundUpgrade, OutboundUpgrade, PeerId, UpgradeInfo};
+use libp2p::swarm::ProtocolsHandlerUpgrErr;
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::{BufReader, Read};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
-use std::{io, iter, pin::Pin};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{io, pin::Pin};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+use crate::contents::{ReceiveSink, SendSource};
+use crate::error::TransferError;
+use crate::hash::{HashAlgorithm, Hasher};
+use crate::header::{hash_manifest, ManifestEntry, TransferHeader, UNKNOWN_SIZE};
+use crate::sanitize::{sanitize_name, sanitize_relative_path, NameError};
+use crate::types::{ContentHash, FileName, FilePath};
 
 const CHUNK_SIZE: usize = 4096;
+/// The 1.0 header has no room for an algorithm, its hash is always SHA-1.
+const LEGACY_HASH_ALGORITHM: &str = "sha1";
+/// Longest line of a 1.0 header, as long as the longest name of a 2.0 one.
+const MAX_LEGACY_LINE_LEN: u64 = u16::MAX as u64;
+
+/// Newline separated name and hash, followed by the contents.
+const PROTOCOL_V1: &str = "/transfer/1.0";
+/// Length-prefixed `TransferHeader`, a one byte answer from the receiver, then the contents.
+/// An accepting answer is followed by a u64 offset, the number of bytes the receiver
+/// already has from an interrupted transfer, and only the rest of the contents is sent.
+/// The contents go in frames, each a u32 length and that many bytes, ended by a
+/// `FRAME_END` length, or by `FRAME_ABORT` when the sender cancels. A stream that stops
+/// before either was interrupted, and the receiver keeps what it got. `FRAME_END` is
+/// followed by a u16 length and the hash of the whole contents, which the sender
+/// computes while streaming them, so the header's hash is left empty. The hash of a
+/// directory starts with its entries, see `hash_manifest`. Once the contents are
+/// verified and stored, the receiver answers with a last `STATUS_KEPT` byte, or
+/// `STATUS_FAILED` when it could not keep them.
+const PROTOCOL_V2: &str = "/transfer/2.0";
+
+const ANSWER_REJECT: u8 = 0;
+const ANSWER_ACCEPT: u8 = 1;
+
+const STATUS_FAILED: u8 = 0;
+const STATUS_KEPT: u8 = 1;
+
+const FRAME_END: u32 = 0;
+const FRAME_ABORT: u32 = u32::MAX;
+const MAX_FRAME_LEN: u32 = 64 * 1024;
+
+/// Longest a peer may take to send the header, or the receiver's answer once the
+/// application decided.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest an offer waits for the application, it is declined after that.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(120);
+/// Longest the contents may stall. An interrupted file is kept to be resumed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Transfers in progress, by id, with the sender that stops each of them.
+pub type RunningTransfers = Arc<Mutex<HashMap<u64, oneshot::Sender<()>>>>;
 
 pub struct FileToSend {
     pub name: String,
-    pub path: String,
+    pub source: SendSource,
+    /// Receiver of the file, `None` means any connected peer.
+    pub peer: Option<PeerId>,
+    /// Broadcast this file belongs to, see `TransferBehaviour::push_broadcast`.
+    pub broadcast: Option<u64>,
+    pub transfer_id: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OfferAnswer {
+    Accept,
+    Reject,
+}
+
+/// Header of an incoming transfer, waiting for the application to answer it.
+#[derive(Debug)]
+pub struct OfferRequest {
+    /// Transfer id, also used by the `Progress` events of this transfer.
+    pub id: u64,
+    pub peer: PeerId,
+    pub header: TransferHeader,
+    pub answer: oneshot::Sender<OfferAnswer>,
 }
 
 #[derive(Clone, Debug)]
 pub enum ProtocolEvent {
     Received {
+        transfer_id: u64,
+        name: FileName,
+        path: FilePath,
+        hash: ContentHash,
+        hash_algorithm: String,
+        size_bytes: usize,
+        /// Whether the contents were checked against the sender's hash. Contents that do
+        /// not match it end up as `Failed` with `TransferError::HashMismatch` instead, only
+        /// the SHA-1 of 1.0 senders is left unchecked.
+        verified: bool,
+        /// The contents when they were received to `ReceiveSink::Memory`, `path` is then
+        /// empty, as it is for `ReceiveSink::Writer`.
+        contents: Option<Arc<Vec<u8>>>,
+    },
+    /// The remote peer sent a name that cannot be safely written to the download directory.
+    InvalidName {
+        transfer_id: u64,
         name: String,
-        path: String,
+        error: NameError,
+    },
+    /// A remote peer wants to send us a file, see `TransferBehaviour::accept`.
+    IncomingOffer {
+        id: u64,
+        peer: PeerId,
+        name: String,
+        /// Only 1.0 senders announce it, 2.0 ones send it after the contents.
         hash: String,
+        /// Zero when the sender does not know how much it is going to send.
         size_bytes: usize,
     },
-    Sent,
+    /// We declined an incoming offer. Offers declined by the remote peer end up as
+    /// `Failed` with `TransferError::Rejected`.
+    Declined { transfer_id: u64, name: String },
+    Sent {
+        transfer_id: u64,
+        name: String,
+        broadcast: Option<u64>,
+    },
+    /// A transfer in either direction did not complete. The name is missing when the
+    /// incoming header could not be read.
+    Failed {
+        transfer_id: u64,
+        name: Option<String>,
+        broadcast: Option<u64>,
+        error: TransferError,
+    },
+    /// One peer of a broadcast is done, successfully or not.
+    BroadcastProgress {
+        id: u64,
+        peer: PeerId,
+        result: Result<(), TransferError>,
+        done: usize,
+        total: usize,
+    },
+    /// Every peer of a broadcast is done.
+    BroadcastFinished {
+        id: u64,
+        name: String,
+        sent: Vec<PeerId>,
+        failed: Vec<PeerId>,
+    },
+    /// Contents sent or received so far, see `TransferBehaviour::set_progress_interval`.
+    /// `bytes_total` is 0 when the sender did not announce it, like for readers of
+    /// unknown size.
+    Progress {
+        transfer_id: u64,
+        bytes_done: u64,
+        bytes_total: u64,
+        /// Bytes per second since the previous update.
+        rate: u64,
+    },
+    /// A transfer in either direction was stopped by `TransferBehaviour::cancel`, on our
+    /// side or, for incoming transfers, on the sender's.
+    Cancelled {
+        transfer_id: u64,
+        name: String,
+        broadcast: Option<u64>,
+    },
+    /// A connection to the peer was established. Refused peers are not reported.
+    Connected { peer: PeerId },
+    /// The last connection to a connected peer was closed.
+    Disconnected { peer: PeerId },
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct TransferPayload {
-    pub name: String,
-    pub path: String,
-    pub hash: String,
+    pub name: FileName,
+    pub path: FilePath,
+    pub hash: ContentHash,
+    /// Algorithm of `hash`: declared by the sender for incoming files, used to compute it
+    /// for outgoing ones.
+    pub hash_algorithm: String,
     pub size_bytes: usize,
+    /// Set on received files whose contents matched `hash`.
+    pub verified: bool,
+    /// Directory incoming files are written to.
+    pub target_path: String,
+    /// Remote peer of the connection, known once the handler is built.
+    pub peer: Option<PeerId>,
+    /// Where incoming offers are sent for approval. Without it every offer is accepted.
+    pub offers: Option<mpsc::UnboundedSender<OfferRequest>>,
+    /// Broadcast an outgoing payload belongs to, echoed back in its result.
+    pub broadcast: Option<u64>,
+    /// Identifies the transfer in the events about it. Set by the behaviour for outgoing
+    /// payloads, taken from `transfer_ids` once an incoming substream is opened.
+    pub transfer_id: u64,
+    /// Counter shared with the behaviour, so ids are unique in both directions.
+    pub transfer_ids: Option<Arc<AtomicU64>>,
+    /// Where `Progress` events are sent, if anywhere.
+    pub progress: Option<mpsc::UnboundedSender<ProtocolEvent>>,
+    /// Minimum time between two `Progress` events of a transfer.
+    pub progress_interval: Duration,
+    /// Where a started transfer registers itself, so the behaviour can cancel it.
+    pub running: Option<RunningTransfers>,
+    /// Contents of an outgoing payload, set on every payload the behaviour sends. They go
+    /// over /transfer/1.0 only to peers without 2.0, see `protocol_info`.
+    pub source: Option<SendSource>,
+    /// Where the contents of incoming files go.
+    pub sink: ReceiveSink,
+    /// Contents of a file received to `ReceiveSink::Memory`.
+    pub contents: Option<Arc<Vec<u8>>>,
 }
 
 impl TransferPayload {
-    pub fn new(name: String, path: String, hash: String, size_bytes: usize) -> TransferPayload {
+    pub fn new(
+        name: FileName,
+        path: FilePath,
+        hash: ContentHash,
+        size_bytes: usize,
+    ) -> TransferPayload {
         TransferPayload {
             name,
             path,
             hash,
             size_bytes,
+            ..TransferPayload::default()
         }
     }
 
-    pub fn check_file(&self) -> Result<(), io::Error> {
-        let mut contents = vec![];
-        let mut file = BufReader::new(File::open(&self.path)?);
-        file.read_to_end(&mut contents).expect("Cannot read file");
-        let hash_from_disk = hash_contents(&mut contents);
+    /// Hashes the file on disk again with the algorithm the sender declared and compares
+    /// it with the hash the sender announced. Received files are already verified while
+    /// they are written, see `ProtocolEvent::Received`, this is for checking them later.
+    pub fn check_file(&self) -> Result<(), TransferError> {
+        let algorithm = self
+            .hash_algorithm
+            .parse::<HashAlgorithm>()
+            .map_err(|e| TransferError::Protocol(e.to_string()))?;
+        let mut hasher = algorithm.hasher();
+        let root = self.path.as_ref();
+        let files = if root.is_dir() {
+            let entries = walk_dir(root)?;
+            hash_manifest(&entries, &mut hasher);
+            entries
+                .into_iter()
+                .map(|entry| root.join(entry.path))
+                .collect()
+        } else {
+            vec![root.to_path_buf()]
+        };
+        let mut buff = vec![0u8; CHUNK_SIZE];
+        for path in files {
+            let mut file = BufReader::new(File::open(path)?);
+            loop {
+                let n = file.read(&mut buff)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buff[..n]);
+            }
+        }
+        let hash_from_disk = hasher.finish();
 
-        if hash_from_disk != self.hash {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "File corrupted!",
-            ))
+        if hash_from_disk != self.hash.as_str() {
+            Err(TransferError::HashMismatch {
+                expected: self.hash.clone(),
+                actual: ContentHash::from(hash_from_disk),
+            })
         } else {
             Ok(())
         }
     }
+
+    /// Hands the offer over to the behaviour and waits for the application to answer it.
+    async fn ask_for_approval(&self, header: &TransferHeader) -> OfferAnswer {
+        let (offers, peer) = match (&self.offers, &self.peer) {
+            (Some(offers), Some(peer)) => (offers, peer),
+            _ => return OfferAnswer::Accept,
+        };
+        let (sender, receiver) = oneshot::channel();
+        let request = OfferRequest {
+            id: self.transfer_id,
+            peer: peer.clone(),
+            header: header.clone(),
+            answer: sender,
+        };
+        if offers.unbounded_send(request).is_err() {
+            return OfferAnswer::Reject;
+        }
+        receiver.await.unwrap_or(OfferAnswer::Reject)
+    }
 }
 
 impl UpgradeInfo for TransferPayload {
     type Info = &'static str;
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = std::vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once("/transfer/1.0")
+        // 1.0 knows no directories and announces a SHA-1 in front of the contents, so
+        // only what can be read twice falls back to it for peers without 2.0.
+        let legacy = match &self.source {
+            None | Some(SendSource::Bytes(_)) => true,
+            Some(SendSource::Path(path)) => !Path::new(path).is_dir(),
+            Some(SendSource::Reader(_)) => false,
+        };
+        if legacy {
+            vec![PROTOCOL_V2, PROTOCOL_V1].into_iter()
+        } else {
+            vec![PROTOCOL_V2].into_iter()
+        }
     }
 }
 
@@ -75,142 +321,2207 @@ fn now() -> Instant {
     Instant::now()
 }
 
-fn add_row(value: &str) -> Vec<u8> {
-    format!("{}\n", value).into_bytes()
+/// A transfer's entry in `running`, through which the behaviour cancels it. The entry
+/// is removed when this is dropped.
+struct Cancellation {
+    running: Option<RunningTransfers>,
+    transfer_id: u64,
+    receiver: Option<oneshot::Receiver<()>>,
+    cancelled: bool,
+}
+
+impl Cancellation {
+    fn register(running: Option<RunningTransfers>, transfer_id: u64) -> Self {
+        let receiver = running.as_ref().map(|running| {
+            let (sender, receiver) = oneshot::channel();
+            if let Ok(mut transfers) = running.lock() {
+                transfers.insert(transfer_id, sender);
+            }
+            receiver
+        });
+        Cancellation {
+            running,
+            transfer_id,
+            receiver,
+            cancelled: false,
+        }
+    }
+
+    /// Runs `step` until it completes, or fails with `Cancelled` as soon as the transfer
+    /// is cancelled. Once cancelled, every later step fails right away.
+    async fn run<T>(
+        &mut self,
+        step: impl Future<Output = Result<T, TransferError>>,
+    ) -> Result<T, TransferError> {
+        if self.cancelled {
+            return Err(TransferError::Cancelled);
+        }
+        let receiver = match &mut self.receiver {
+            Some(receiver) => receiver,
+            None => return step.await,
+        };
+        futures::pin_mut!(step);
+        match future::select(step, receiver).await {
+            Either::Left((result, _)) => result,
+            Either::Right((Ok(()), _)) => {
+                self.receiver = None;
+                self.cancelled = true;
+                Err(TransferError::Cancelled)
+            }
+            // The behaviour is gone, nothing can cancel the transfer anymore.
+            Either::Right((Err(_), step)) => {
+                self.receiver = None;
+                step.await
+            }
+        }
+    }
+
+    /// Whether the transfer was cancelled, without waiting.
+    fn is_cancelled(&mut self) -> bool {
+        if let Some(receiver) = &mut self.receiver {
+            match receiver.try_recv() {
+                Ok(Some(())) => self.cancelled = true,
+                Ok(None) => {}
+                Err(_) => self.receiver = None,
+            }
+        }
+        self.cancelled
+    }
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        if let Some(Ok(mut transfers)) = self.running.as_ref().map(|running| running.lock()) {
+            transfers.remove(&self.transfer_id);
+        }
+    }
+}
+
+/// Runs `step`, failing with a `TimedOut` error when it takes longer than `timeout`.
+async fn timed<T>(
+    timeout: Duration,
+    what: &str,
+    step: impl Future<Output = Result<T, TransferError>>,
+) -> Result<T, TransferError> {
+    futures::pin_mut!(step);
+    match future::select(step, Delay::new(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Timed out {} after {:?}", what, timeout),
+        )
+        .into()),
+    }
+}
+
+/// Sends the `Progress` events of one transfer, at most once per interval and once more
+/// when the last byte is done.
+struct ProgressReporter {
+    transfer_id: u64,
+    bytes_done: u64,
+    bytes_total: u64,
+    interval: Duration,
+    events: Option<mpsc::UnboundedSender<ProtocolEvent>>,
+    reported_at: Instant,
+    reported_bytes: u64,
+}
+
+impl ProgressReporter {
+    fn new(payload: &TransferPayload, bytes_done: u64, bytes_total: u64) -> Self {
+        ProgressReporter {
+            transfer_id: payload.transfer_id,
+            bytes_done,
+            bytes_total,
+            interval: payload.progress_interval,
+            events: payload.progress.clone(),
+            reported_at: now(),
+            reported_bytes: bytes_done,
+        }
+    }
+
+    fn advance(&mut self, bytes: usize) {
+        self.bytes_done += bytes as u64;
+        let events = match &self.events {
+            Some(events) => events,
+            None => return,
+        };
+        let elapsed = self.reported_at.elapsed();
+        if elapsed < self.interval && self.bytes_done != self.bytes_total {
+            return;
+        }
+        let seconds = elapsed.as_secs_f64().max(0.001);
+        let event = ProtocolEvent::Progress {
+            transfer_id: self.transfer_id,
+            bytes_done: self.bytes_done,
+            bytes_total: self.bytes_total,
+            rate: ((self.bytes_done - self.reported_bytes) as f64 / seconds) as u64,
+        };
+        // The behaviour is gone when this fails, nobody is left to tell.
+        let _ = events.unbounded_send(event);
+        self.reported_at = now();
+        self.reported_bytes = self.bytes_done;
+    }
+}
+
+/// Regular files under `root`, sorted by their `/` separated relative path. This is
+/// the order they are hashed and sent in. Symbolic links are skipped.
+fn walk_dir(root: &Path) -> Result<Vec<ManifestEntry>, io::Error> {
+    let mut entries = vec![];
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().map_err(|name| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is not valid UTF-8", name),
+                )
+            })?;
+            let path = if dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file() {
+                let size = entry.metadata()?.len();
+                entries.push(ManifestEntry { path, size });
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Sends the contents of an outgoing transfer in frames, see `PROTOCOL_V2`, hashing
+/// them on the way.
+struct ContentsWriter<'a, S> {
+    socket: &'a mut S,
+    cancellation: Cancellation,
+    progress: ProgressReporter,
+    hasher: Hasher,
+    /// Whether the contents go in frames, 1.0 sends them as they are.
+    framed: bool,
+}
+
+impl<S: AsyncWrite + Unpin> ContentsWriter<'_, S> {
+    /// Runs a step of the transfer that does not write to the socket. If the transfer
+    /// is cancelled meanwhile, the receiver is told so.
+    async fn run<T>(
+        &mut self,
+        step: impl Future<Output = Result<T, TransferError>>,
+    ) -> Result<T, TransferError> {
+        match self.cancellation.run(step).await {
+            Err(TransferError::Cancelled) => self.abort().await,
+            result => result,
+        }
+    }
+
+    /// Streams `len` bytes of the file at `path`, starting at `offset`.
+    async fn write_file(
+        &mut self,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), TransferError> {
+        let file = self.run(AsyncFile::open(path).err_into()).await?;
+        let mut file = asyncio::BufReader::new(file);
+        self.skip(&mut file, offset).await?;
+        let sent = self.write_contents(file, len).await?;
+        if sent != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{:?} changed while it was sent", path),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Reads past the first `len` bytes of `reader`, which the receiver already has
+    /// from an interrupted transfer. They are hashed all the same, the hash covers the
+    /// whole contents.
+    async fn skip(
+        &mut self,
+        reader: impl AsyncRead + Unpin,
+        len: u64,
+    ) -> Result<(), TransferError> {
+        let mut reader = reader.take(len);
+        let mut buff = vec![0u8; CHUNK_SIZE];
+        let mut skipped = 0;
+        loop {
+            let n = self.run(reader.read(&mut buff).err_into()).await?;
+            if n == 0 {
+                break;
+            }
+            self.hasher.update(&buff[..n]);
+            skipped += n as u64;
+        }
+        if skipped != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Contents ended after {} of the {} bytes to skip",
+                    skipped, len
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Sends at most `len` bytes of `reader`, returns how many there were. Cancelling
+    /// the transfer is noticed between two frames.
+    async fn write_contents(
+        &mut self,
+        reader: impl AsyncRead + Unpin,
+        len: u64,
+    ) -> Result<u64, TransferError> {
+        let mut reader = reader.take(len);
+        // Room for the length in front of the data, so a frame is a single write.
+        let mut frame = vec![0u8; 4 + CHUNK_SIZE];
+        let mut sent = 0;
+        loop {
+            let n = self.run(reader.read(&mut frame[4..]).err_into()).await?;
+            if n == 0 {
+                break;
+            }
+            if self.cancellation.is_cancelled() {
+                return self.abort().await;
+            }
+            self.hasher.update(&frame[4..4 + n]);
+            frame[..4].copy_from_slice(&(n as u32).to_be_bytes());
+            let start = if self.framed { 0 } else { 4 };
+            self.write(&frame[start..4 + n]).await?;
+            sent += n as u64;
+            self.progress.advance(n);
+        }
+        Ok(sent)
+    }
+
+    /// Tells the receiver the contents are complete and what they hash to.
+    async fn finish(self) -> Result<(), TransferError> {
+        let ContentsWriter {
+            socket,
+            hasher,
+            framed,
+            ..
+        } = self;
+        if !framed {
+            socket.close().await?;
+            return Ok(());
+        }
+        let hash = hasher.finish();
+        let trailer = [
+            &FRAME_END.to_be_bytes()[..],
+            &(hash.len() as u16).to_be_bytes(),
+            hash.as_bytes(),
+        ]
+        .concat();
+        let write = socket.write_all(&trailer).err_into();
+        timed(IDLE_TIMEOUT, "sending the contents", write).await?;
+        socket.close().await?;
+        Ok(())
+    }
+
+    /// Tells the receiver the transfer was cancelled, so it drops what it received.
+    async fn abort<T>(&mut self) -> Result<T, TransferError> {
+        info!("Cancelled, telling the receiver");
+        // 1.0 has no way to tell, its receiver finds the contents incomplete.
+        if self.framed {
+            self.write(&FRAME_ABORT.to_be_bytes()).await?;
+        }
+        self.socket.close().await?;
+        Err(TransferError::Cancelled)
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), TransferError> {
+        let write = self.socket.write_all(bytes).err_into();
+        timed(IDLE_TIMEOUT, "sending the contents", write).await
+    }
+}
+
+/// Reads the contents of an incoming transfer: the frames of `PROTOCOL_V2`, or for 1.0
+/// everything up to the end of the stream.
+struct ContentsReader<'a, R> {
+    reader: R,
+    cancellation: &'a mut Cancellation,
+    /// Where the 2.0 frames are, `None` for 1.0.
+    frames: Option<Frames>,
+    ended: bool,
 }
 
-fn hash_contents(contents: &Vec<u8>) -> String {
-    let mut hasher = Sha1::new();
-    hasher.input(&contents);
-    hasher.result_str()
+impl<'a, R: AsyncRead + Unpin> ContentsReader<'a, R> {
+    /// `len` is what the 2.0 header announced, less what the receiver already has.
+    fn new(reader: R, protocol: &str, len: u64, cancellation: &'a mut Cancellation) -> Self {
+        let frames = Frames {
+            left: 0,
+            remaining: len,
+            hash: None,
+        };
+        ContentsReader {
+            reader,
+            cancellation,
+            frames: Some(frames).filter(|_| protocol == PROTOCOL_V2),
+            ended: false,
+        }
+    }
+
+    /// Reads the next part of the contents into `buff`, 0 once they are complete. Fails
+    /// with `Cancelled` when either side cancelled the transfer, with an `UnexpectedEof`
+    /// error when the stream stops before the contents are complete, and with a protocol
+    /// error when the sender sends more than it announced.
+    async fn read(&mut self, buff: &mut [u8]) -> Result<usize, TransferError> {
+        if self.ended {
+            return Ok(0);
+        }
+        let ContentsReader {
+            reader,
+            cancellation,
+            frames,
+            ended,
+        } = self;
+        let what = "waiting for the contents";
+        let n = match frames {
+            Some(frames) => {
+                let read = timed(IDLE_TIMEOUT, what, frames.read(reader, buff));
+                cancellation.run(read).await?
+            }
+            None => {
+                let read = timed(IDLE_TIMEOUT, what, reader.read(buff).err_into());
+                cancellation.run(read).await?
+            }
+        };
+        *ended = n == 0;
+        Ok(n)
+    }
+
+    /// Hash of the contents the sender declared: in the trailer of 2.0 transfers, in the
+    /// header of 1.0 ones.
+    fn hash<'h>(&'h self, header: &'h TransferHeader) -> &'h str {
+        match self.frames.as_ref().and_then(|frames| frames.hash.as_ref()) {
+            Some(hash) => hash,
+            None => &header.hash,
+        }
+    }
+}
+
+/// Where a 2.0 receiver is in the frames of the contents.
+struct Frames {
+    /// Bytes left of the current frame.
+    left: usize,
+    /// Bytes the sender has yet to send, frames beyond them are refused.
+    remaining: u64,
+    /// From the trailer, once the contents ended.
+    hash: Option<String>,
+}
+
+impl Frames {
+    async fn read(
+        &mut self,
+        reader: &mut (impl AsyncRead + Unpin),
+        buff: &mut [u8],
+    ) -> Result<usize, TransferError> {
+        if self.left == 0 {
+            let mut len = [0u8; 4];
+            read_exact(reader, &mut len).await?;
+            match u32::from_be_bytes(len) {
+                FRAME_END => {
+                    let mut len = [0u8; 2];
+                    read_exact(reader, &mut len).await?;
+                    let mut hash = vec![0u8; u16::from_be_bytes(len) as usize];
+                    read_exact(reader, &mut hash).await?;
+                    let hash = String::from_utf8(hash).map_err(|_| {
+                        TransferError::Protocol("The hash is not valid UTF-8".to_string())
+                    })?;
+                    self.hash = Some(hash);
+                    return Ok(0);
+                }
+                FRAME_ABORT => {
+                    info!("The sender cancelled");
+                    return Err(TransferError::Cancelled);
+                }
+                len if len > MAX_FRAME_LEN => {
+                    return Err(TransferError::Protocol(format!(
+                        "Frame of {} bytes, at most {} are allowed",
+                        len, MAX_FRAME_LEN
+                    )))
+                }
+                len if u64::from(len) > self.remaining => {
+                    return Err(TransferError::Protocol(format!(
+                        "Frame of {} bytes with only {} left to send",
+                        len, self.remaining
+                    )))
+                }
+                len => {
+                    self.left = len as usize;
+                    self.remaining -= u64::from(len);
+                }
+            }
+        }
+        let len = buff.len().min(self.left);
+        let n = reader.read(&mut buff[..len]).await?;
+        if n == 0 {
+            return Err(interrupted().into());
+        }
+        self.left -= n;
+        Ok(n)
+    }
+}
+
+fn interrupted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "The stream stopped before the contents were complete",
+    )
+}
+
+/// `read_exact` that reports the end of the stream as an interrupted transfer.
+async fn read_exact(
+    reader: &mut (impl AsyncRead + Unpin),
+    buff: &mut [u8],
+) -> Result<(), io::Error> {
+    reader.read_exact(buff).await.map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => interrupted(),
+        _ => e,
+    })
+}
+
+/// Contents of an outgoing transfer, streamed once the receiver answered the header.
+enum Outgoing {
+    /// A file, or a directory whose files are listed in the header.
+    Path(PathBuf),
+    Bytes(Arc<Vec<u8>>),
+    Reader(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+/// Reads what the header needs from `source`: the entries of a directory and the size
+/// of the contents.
+async fn prepare_source(
+    source: SendSource,
+) -> Result<(Outgoing, Vec<ManifestEntry>, u64), TransferError> {
+    let bytes = match source {
+        SendSource::Path(path) => {
+            // A directory goes out as its files, one after another, listed in the header.
+            let root = PathBuf::from(path);
+            let entries = if root.is_dir() {
+                walk_dir(&root)?
+            } else {
+                vec![]
+            };
+            if root.is_dir() && entries.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("There are no files in {:?}", root),
+                )
+                .into());
+            }
+            let size = if entries.is_empty() {
+                asyncfs::metadata(&root).await?.len()
+            } else {
+                entries.iter().map(|entry| entry.size).sum()
+            };
+            return Ok((Outgoing::Path(root), entries, size));
+        }
+        SendSource::Bytes(bytes) => bytes,
+        SendSource::Reader(shared) => {
+            let size = shared.size.unwrap_or(UNKNOWN_SIZE);
+            return Ok((Outgoing::Reader(shared.take()?), vec![], size));
+        }
+    };
+    let size = bytes.len() as u64;
+    Ok((Outgoing::Bytes(bytes), vec![], size))
+}
+
+async fn update_from_file(hasher: &mut Hasher, path: &Path) -> Result<(), io::Error> {
+    let file = AsyncFile::open(path).await?;
+    let mut reader = asyncio::BufReader::new(file);
+    let mut buff = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buff).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buff[..n]);
+    }
+    Ok(())
+}
+
+async fn read_header_v1(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> Result<TransferHeader, io::Error> {
+    let name = read_legacy_line(reader).await?;
+    let hash = read_legacy_line(reader).await?;
+
+    Ok(TransferHeader {
+        name: name.trim().to_string(),
+        hash_algorithm: LEGACY_HASH_ALGORITHM.to_string(),
+        hash: hash.trim().to_string(),
+        ..TransferHeader::default()
+    })
+}
+
+/// A line of the 1.0 header, refused once it runs past `MAX_LEGACY_LINE_LEN` so a peer
+/// cannot make us buffer an endless one.
+async fn read_legacy_line(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<String, io::Error> {
+    let mut line = String::new();
+    reader
+        .take(MAX_LEGACY_LINE_LEN + 1)
+        .read_line(&mut line)
+        .await?;
+    if line.len() as u64 > MAX_LEGACY_LINE_LEN && !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Header line over {} bytes", MAX_LEGACY_LINE_LEN),
+        ));
+    }
+    Ok(line)
+}
+
+/// SHA-1 of a file or of contents in memory, which 1.0 announces before the contents.
+async fn legacy_hash(contents: &mut Outgoing) -> Result<String, TransferError> {
+    let mut hasher = sha1::Sha1::new();
+    match contents {
+        Outgoing::Path(path) => {
+            let mut file = asyncio::BufReader::new(AsyncFile::open(path).await?);
+            let mut buff = vec![0u8; CHUNK_SIZE];
+            loop {
+                let n = file.read(&mut buff).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buff[..n]);
+            }
+        }
+        Outgoing::Bytes(bytes) => hasher.update(bytes),
+        Outgoing::Reader(_) => {
+            return Err(TransferError::Protocol(
+                "Readers cannot be sent over 1.0".to_string(),
+            ))
+        }
+    }
+    Ok(hasher.digest().to_string())
+}
+
+async fn read_header(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    protocol: &str,
+) -> Result<TransferHeader, io::Error> {
+    if protocol == PROTOCOL_V1 {
+        read_header_v1(reader).await
+    } else {
+        TransferHeader::read_from(reader).await
+    }
+}
+
+/// `.part` files and directories incoming transfers are writing to, see `Parts::claim`.
+static PARTS_IN_USE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Partial download of an incoming transfer and its state file. Both are named after
+/// the sender and the file, so an interrupted transfer of the same file from the same
+/// peer finds them again.
+struct Parts {
+    part_path: PathBuf,
+    state_path: PathBuf,
+    /// Whether the part is kept for a later transfer when this one is interrupted.
+    resumable: bool,
+}
+
+impl Parts {
+    /// The parts of `header` sent by `peer` over `protocol`. When another transfer is
+    /// already writing to them, this one gets parts of its own, which are never resumed.
+    /// Neither are contents of unknown size, the sender could not tell how much of them
+    /// is left, nor anything sent over 1.0, which has no offset to resume from.
+    fn claim(
+        target_path: &str,
+        peer: Option<&PeerId>,
+        header: &TransferHeader,
+        protocol: &str,
+        transfer_id: u64,
+    ) -> Parts {
+        // Everything in the key comes from the remote peer, hashing it leaves only
+        // what is safe in a file name.
+        let peer = peer.map(PeerId::to_base58).unwrap_or_default();
+        let size = header.size.to_string();
+        let mut hasher = HashAlgorithm::Blake3.hasher();
+        for field in [
+            &peer,
+            &header.name,
+            &size,
+            &header.hash_algorithm,
+            &header.hash,
+        ] {
+            hasher.update(field.as_bytes());
+            hasher.update(b"\n");
+        }
+        let key = hasher.finish();
+
+        let dir = Path::new(target_path);
+        let mut base = format!("{}.{}", header.name, &key[..16]);
+        let mut in_use = PARTS_IN_USE.lock().unwrap_or_else(PoisonError::into_inner);
+        let resumable = protocol == PROTOCOL_V2
+            && header.known_size().is_some()
+            && !in_use.contains(&dir.join(format!("{}.part", base)));
+        if !resumable {
+            base = format!("{}.{}", base, transfer_id);
+        }
+        let part_path = dir.join(format!("{}.part", base));
+        in_use.push(part_path.clone());
+        Parts {
+            part_path,
+            state_path: dir.join(format!("{}.part.state", base)),
+            resumable,
+        }
+    }
+
+    fn state(header: &TransferHeader) -> String {
+        format!(
+            "{}\n{}\n{}\n",
+            header.hash_algorithm, header.hash, header.size
+        )
+    }
+
+    /// Removes what the transfer left behind, it will not be resumed.
+    async fn remove(&self) -> Result<(), io::Error> {
+        let result = if self.part_path.is_dir() {
+            asyncfs::remove_dir_all(&self.part_path).await
+        } else {
+            asyncfs::remove_file(&self.part_path).await
+        };
+        match result.and(asyncfs::remove_file(&self.state_path).await) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of bytes of this file kept from an earlier, interrupted transfer.
+    async fn resume_offset(&self, header: &TransferHeader) -> u64 {
+        if !self.resumable {
+            return 0;
+        }
+        match asyncfs::read_to_string(&self.state_path).await {
+            Ok(state) if state == Parts::state(header) => {}
+            _ => return 0,
+        }
+        match asyncfs::metadata(&self.part_path).await {
+            Ok(metadata) if metadata.len() <= header.size => metadata.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl Drop for Parts {
+    fn drop(&mut self) {
+        let mut in_use = PARTS_IN_USE.lock().unwrap_or_else(PoisonError::into_inner);
+        in_use.retain(|path| *path != self.part_path);
+    }
 }
 
 async fn read_socket(
-    socket: impl AsyncRead + AsyncWrite + Send + Unpin,
-) -> Result<TransferPayload, io::Error> {
-    let mut reader = asyncio::BufReader::new(socket);
+    reader: &mut ContentsReader<'_, impl AsyncRead + Unpin>,
+    header: TransferHeader,
+    protocol: &str,
+    target_path: &str,
+    parts: &Parts,
+    offset: u64,
+    mut progress: ProgressReporter,
+) -> Result<TransferPayload, TransferError> {
     let mut payloads: Vec<u8> = vec![];
 
-    let mut name: String = "".into();
-    let mut hash: String = "".into();
-    reader.read_line(&mut name).await?;
-    reader.read_line(&mut hash).await?;
+    let name = header.name.as_str();
+    debug!("Name: {}, Size: {}", name, header.size);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
 
-    let (name, hash) = (name.trim(), hash.trim());
-    println!("Name: {}, Hash: {}", name, hash);
-    let now = SystemTime::now();
-    let timestamp = now.duration_since(UNIX_EPOCH).expect("Time failed");
+    asyncfs::create_dir_all(target_path).await?;
+    let path = Path::new(target_path)
+        .join(format!("{}_{}", timestamp.as_secs(), name))
+        .to_string_lossy()
+        .to_string();
 
-    let path = format!("/tmp/files/{}_{}", timestamp.as_secs(), name);
+    // Contents go to a `.part` file first, which is kept when the connection
+    // drops so the next transfer of the same file can continue from there.
+    let Parts {
+        part_path,
+        state_path,
+        ..
+    } = parts;
+    let file = if offset > 0 {
+        info!("Resuming {} from {} bytes", name, offset);
+        asyncfs::OpenOptions::new()
+            .append(true)
+            .open(part_path)
+            .await?
+    } else {
+        asyncfs::write(state_path, Parts::state(&header)).await?;
+        AsyncFile::create(part_path).await?
+    };
+
+    // Contents are hashed on their way to disk. A resumed transfer hashes what it
+    // already has first, so the result still covers the whole file. 1.0 senders
+    // declare SHA-1, which is left unverified.
+    let mut hasher = header
+        .hash_algorithm
+        .parse::<HashAlgorithm>()
+        .ok()
+        .map(|algorithm| algorithm.hasher());
+    if let (Some(hasher), true) = (&mut hasher, offset > 0) {
+        update_from_file(hasher, part_path).await?;
+    }
 
-    let mut file = asyncio::BufWriter::new(AsyncFile::create(&path).await?);
+    let mut file = asyncio::BufWriter::new(file);
     let mut counter: usize = 0;
-    loop {
+    let result = loop {
         let mut buff = vec![0u8; CHUNK_SIZE];
         match reader.read(&mut buff).await {
+            Ok(0) => break Ok(()),
             Ok(n) => {
-                if n > 0 {
-                    payloads.extend(&buff[..n]);
-                    counter += n;
-                    if payloads.len() >= (CHUNK_SIZE * 256) {
-                        file.write_all(&payloads)
-                            .await
-                            .expect("Writing file failed");
-                        file.flush().await?;
-                        payloads.clear();
-                    }
-                } else {
-                    file.write_all(&payloads).await.expect("Writing file failed");
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&buff[..n]);
+                }
+                payloads.extend(&buff[..n]);
+                counter += n;
+                progress.advance(n);
+                if payloads.len() >= (CHUNK_SIZE * 256) {
+                    file.write_all(&payloads).await?;
                     file.flush().await?;
                     payloads.clear();
-                    break;
                 }
             }
-            Err(e) => panic!("Failed reading the socket {:?}", e),
+            Err(e) => break Err(e),
+        }
+    };
+    file.write_all(&payloads).await?;
+    file.flush().await?;
+    match result {
+        // What was received is removed along with the state, by the caller.
+        Err(TransferError::Cancelled) => return Err(TransferError::Cancelled),
+        Err(e @ TransferError::Io(_)) if parts.resumable => {
+            warn!("Failed reading the socket, keeping {:?}", part_path);
+            return Err(e);
+        }
+        // Not resumed, or the sender broke the protocol and what it sent is not worth
+        // resuming.
+        Err(e) => {
+            parts.remove().await?;
+            return Err(e);
+        }
+        Ok(()) => {}
+    }
+
+    // Only the 2.0 header announces the size up front.
+    let total = offset + counter as u64;
+    if protocol == PROTOCOL_V2 && header.known_size().is_some_and(|size| size != total) {
+        parts.remove().await?;
+        return Err(TransferError::Protocol(format!(
+            "Expected {} bytes, got {}",
+            header.size, total
+        )));
+    }
+
+    let hash = reader.hash(&header);
+    let verified = match verify(hasher, hash) {
+        Ok(verified) => verified,
+        Err(e) => {
+            parts.remove().await?;
+            return Err(e);
         }
+    };
+    asyncfs::rename(part_path, &path).await?;
+    asyncfs::remove_file(state_path).await?;
+    let mut event = TransferPayload::new(
+        FileName::from(name),
+        FilePath::from(path),
+        ContentHash::from(hash),
+        total as usize,
+    );
+    event.hash_algorithm = header.hash_algorithm.clone();
+    event.verified = verified;
+
+    info!(
+        "Name: {}, Read {:?} bytes, hash matched: {}",
+        name, counter, verified
+    );
+    Ok(event)
+}
+
+/// Receives a file to a sink other than the disk. Nothing is kept when the transfer
+/// is interrupted, so it is never resumed.
+async fn read_to_sink(
+    reader: &mut ContentsReader<'_, impl AsyncRead + Unpin>,
+    header: TransferHeader,
+    protocol: &str,
+    sink: &ReceiveSink,
+    mut progress: ProgressReporter,
+) -> Result<TransferPayload, TransferError> {
+    let mut hasher = header
+        .hash_algorithm
+        .parse::<HashAlgorithm>()
+        .ok()
+        .map(|algorithm| algorithm.hasher());
+
+    let (total, contents) = match sink {
+        ReceiveSink::Writer(open) => {
+            let mut writer = open(&header)?;
+            let result = copy_to_sink(reader, &mut writer, None, &mut hasher, &mut progress).await;
+            writer.close().await?;
+            (result?, None)
+        }
+        _ => {
+            let limit = match sink {
+                ReceiveSink::Memory { limit } => Some(*limit),
+                _ => None,
+            };
+            let mut buffer = vec![];
+            let total =
+                copy_to_sink(reader, &mut buffer, limit, &mut hasher, &mut progress).await?;
+            (total, Some(Arc::new(buffer)))
+        }
+    };
+
+    if protocol == PROTOCOL_V2 && header.known_size().is_some_and(|size| size != total) {
+        return Err(TransferError::Protocol(format!(
+            "Expected {} bytes, got {}",
+            header.size, total
+        )));
     }
 
-    let event = TransferPayload::new(
-        name.to_string(),
-        hash.to_string(),
-        path.to_string(),
-        counter,
+    let hash = reader.hash(&header);
+    let verified = verify(hasher, hash)?;
+    let mut event = TransferPayload::new(
+        FileName::from(header.name.as_str()),
+        FilePath::default(),
+        ContentHash::from(hash),
+        total as usize,
     );
+    event.hash_algorithm = header.hash_algorithm.clone();
+    event.verified = verified;
+    event.contents = contents;
 
-    println!("Name: {}, Read {:?} bytes", name, counter);
+    info!(
+        "Name: {}, Read {} bytes to {:?}, hash matched: {}",
+        header.name, total, sink, verified
+    );
     Ok(event)
 }
 
+/// Compares what the contents hashed to with the hash the sender declared. `Ok(false)`
+/// when there is no `hasher`, the algorithm of 1.0 senders is not verified.
+fn verify(hasher: Option<Hasher>, expected: &str) -> Result<bool, TransferError> {
+    let actual = match hasher {
+        Some(hasher) => hasher.finish(),
+        None => return Ok(false),
+    };
+    if actual != expected {
+        return Err(TransferError::HashMismatch {
+            expected: ContentHash::from(expected),
+            actual: ContentHash::from(actual),
+        });
+    }
+    Ok(true)
+}
+
+async fn copy_to_sink(
+    reader: &mut ContentsReader<'_, impl AsyncRead + Unpin>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    limit: Option<u64>,
+    hasher: &mut Option<Hasher>,
+    progress: &mut ProgressReporter,
+) -> Result<u64, TransferError> {
+    let mut buff = vec![0u8; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buff).await?;
+        if n == 0 {
+            break;
+        }
+        if let Some(limit) = limit.filter(|&limit| total + n as u64 > limit) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Contents exceed the limit of {} bytes", limit),
+            )
+            .into());
+        }
+        if let Some(hasher) = hasher {
+            hasher.update(&buff[..n]);
+        }
+        writer.write_all(&buff[..n]).await?;
+        total += n as u64;
+        progress.advance(n);
+    }
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Receives the files of a directory transfer in the order of the manifest. They are
+/// written to a `.part` directory, renamed once everything arrived. Unlike single
+/// files, directories are not resumed.
+async fn read_directory(
+    reader: &mut ContentsReader<'_, impl AsyncRead + Unpin>,
+    header: TransferHeader,
+    entries: Vec<PathBuf>,
+    target_path: &str,
+    parts: &Parts,
+    mut progress: ProgressReporter,
+) -> Result<TransferPayload, TransferError> {
+    let size: u64 = header.entries.iter().map(|entry| entry.size).sum();
+    if size != header.size {
+        return Err(TransferError::Protocol(format!(
+            "Entries add up to {} bytes, the header announced {}",
+            size, header.size
+        )));
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = Path::new(target_path).join(format!("{}_{}", timestamp.as_secs(), header.name));
+    let part_path = &parts.part_path;
+    if asyncfs::metadata(part_path).await.is_ok() {
+        asyncfs::remove_dir_all(part_path).await?;
+    }
+    asyncfs::create_dir_all(part_path).await?;
+
+    let mut hasher = header
+        .hash_algorithm
+        .parse::<HashAlgorithm>()
+        .ok()
+        .map(|algorithm| algorithm.hasher());
+    if let Some(hasher) = &mut hasher {
+        hash_manifest(&header.entries, hasher);
+    }
+    let result = read_entries(
+        reader,
+        &header,
+        &entries,
+        part_path,
+        &mut hasher,
+        &mut progress,
+    )
+    .await;
+    let hash = reader.hash(&header);
+    let verified = match result.and_then(|()| verify(hasher, hash)) {
+        Ok(verified) => verified,
+        Err(e) => {
+            warn!(
+                "Receiving {:?} failed, removing {:?}",
+                header.name, part_path
+            );
+            asyncfs::remove_dir_all(part_path).await?;
+            return Err(e);
+        }
+    };
+    asyncfs::rename(part_path, &path).await?;
+    let mut event = TransferPayload::new(
+        FileName::from(header.name.as_str()),
+        FilePath::from(path.to_string_lossy().to_string()),
+        ContentHash::from(hash),
+        size as usize,
+    );
+    event.hash_algorithm = header.hash_algorithm.clone();
+    event.verified = verified;
+
+    info!(
+        "Name: {}, Read {} files, {} bytes, hash matched: {}",
+        header.name,
+        entries.len(),
+        size,
+        verified
+    );
+    Ok(event)
+}
+
+async fn read_entries(
+    reader: &mut ContentsReader<'_, impl AsyncRead + Unpin>,
+    header: &TransferHeader,
+    entries: &[PathBuf],
+    part_path: &Path,
+    hasher: &mut Option<Hasher>,
+    progress: &mut ProgressReporter,
+) -> Result<(), TransferError> {
+    let mut buff = vec![0u8; CHUNK_SIZE];
+    for (relative, entry) in entries.iter().zip(&header.entries) {
+        let path = part_path.join(relative);
+        if let Some(parent) = path.parent() {
+            asyncfs::create_dir_all(parent).await?;
+        }
+        let mut file = asyncio::BufWriter::new(AsyncFile::create(&path).await?);
+        let mut remaining = entry.size;
+        while remaining > 0 {
+            let len = remaining.min(CHUNK_SIZE as u64) as usize;
+            let n = reader.read(&mut buff[..len]).await?;
+            if n == 0 {
+                return Err(TransferError::Protocol(format!(
+                    "Contents of {:?} ended {} bytes early",
+                    entry.path, remaining
+                )));
+            }
+            if let Some(hasher) = hasher {
+                hasher.update(&buff[..n]);
+            }
+            file.write_all(&buff[..n]).await?;
+            remaining -= n as u64;
+            progress.advance(n);
+        }
+        file.flush().await?;
+    }
+    if reader.read(&mut buff).await? > 0 {
+        return Err(TransferError::Protocol(
+            "More contents than the entries announced".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+impl TransferPayload {
+    async fn receive<TSocket>(
+        self,
+        mut reader: asyncio::BufReader<TSocket>,
+        mut header: TransferHeader,
+        info: &str,
+    ) -> Result<ProtocolEvent, TransferError>
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin,
+    {
+        header.name = match sanitize_name(&header.name) {
+            Ok(name) => name,
+            Err(error) => {
+                warn!("Rejected name {:?}: {}", header.name, error);
+                refuse_offer(reader.get_mut(), info).await;
+                return Ok(ProtocolEvent::InvalidName {
+                    transfer_id: self.transfer_id,
+                    name: header.name,
+                    error,
+                });
+            }
+        };
+
+        let entries = header
+            .entries
+            .iter()
+            .map(|entry| sanitize_relative_path(&entry.path).map_err(|e| (entry.path.clone(), e)))
+            .collect::<Result<Vec<PathBuf>, _>>();
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err((name, error)) => {
+                warn!("Rejected entry {:?} of {:?}: {}", name, header.name, error);
+                refuse_offer(reader.get_mut(), info).await;
+                return Ok(ProtocolEvent::InvalidName {
+                    transfer_id: self.transfer_id,
+                    name,
+                    error,
+                });
+            }
+        };
+
+        let transfer_id = self.transfer_id;
+        let mut cancellation = Cancellation::register(self.running.clone(), transfer_id);
+        let parts = Parts::claim(
+            &self.target_path,
+            self.peer.as_ref(),
+            &header,
+            info,
+            transfer_id,
+        );
+        let name = header.name.clone();
+        let transfer =
+            self.accept_contents(reader, header, entries, info, &parts, &mut cancellation);
+        match transfer.await {
+            Err(TransferError::Cancelled) => {
+                info!("Cancelled {:?}, removing what was received", name);
+                parts.remove().await?;
+                Ok(ProtocolEvent::Cancelled {
+                    transfer_id,
+                    name,
+                    broadcast: None,
+                })
+            }
+            result => result,
+        }
+    }
+
+    /// Asks for approval, answers the sender and reads the contents.
+    async fn accept_contents<TSocket>(
+        self,
+        mut reader: asyncio::BufReader<TSocket>,
+        header: TransferHeader,
+        entries: Vec<PathBuf>,
+        info: &str,
+        parts: &Parts,
+        cancellation: &mut Cancellation,
+    ) -> Result<ProtocolEvent, TransferError>
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start = now();
+
+        // 1.0 senders have no say in the algorithm, anything else must be one we can verify.
+        let mut expired = None;
+        let answer = match header.hash_algorithm.parse::<HashAlgorithm>() {
+            Err(error) if info == PROTOCOL_V2 => {
+                warn!("Refusing {:?}: {}", header.name, error);
+                OfferAnswer::Reject
+            }
+            _ if !entries.is_empty() && !matches!(self.sink, ReceiveSink::Disk) => {
+                warn!(
+                    "Refusing {:?}: directories can only go to disk",
+                    header.name
+                );
+                OfferAnswer::Reject
+            }
+            _ if matches!(self.sink, ReceiveSink::Memory { limit } if header.known_size().is_some_and(|size| size > limit)) =>
+            {
+                warn!(
+                    "Refusing {:?}: {} bytes do not fit in memory",
+                    header.name, header.size
+                );
+                OfferAnswer::Reject
+            }
+            _ => {
+                let approval = self.ask_for_approval(&header).map(Ok);
+                let what = "waiting for an answer to the offer";
+                match cancellation.run(timed(OFFER_TIMEOUT, what, approval)).await {
+                    Ok(answer) => answer,
+                    Err(TransferError::Cancelled) => return Err(TransferError::Cancelled),
+                    Err(error) => {
+                        warn!("Declining {:?}: {}", header.name, error);
+                        expired = Some(error);
+                        OfferAnswer::Reject
+                    }
+                }
+            }
+        };
+        let mut offset = 0;
+        if info == PROTOCOL_V2 {
+            let reply = match answer {
+                OfferAnswer::Accept => {
+                    if matches!(self.sink, ReceiveSink::Disk) && entries.is_empty() {
+                        offset = parts.resume_offset(&header).await;
+                    }
+                    [&[ANSWER_ACCEPT][..], &offset.to_be_bytes()].concat()
+                }
+                OfferAnswer::Reject => vec![ANSWER_REJECT],
+            };
+            let socket = reader.get_mut();
+            let write = async {
+                socket.write_all(&reply).await?;
+                socket.flush().await
+            };
+            timed(HEADER_TIMEOUT, "answering the offer", write.err_into()).await?;
+        }
+        if answer == OfferAnswer::Reject {
+            info!("Declined {:?}", header.name);
+            reader.get_mut().close().await?;
+            // Unanswered offers fail, only the application declines them.
+            if let Some(error) = expired {
+                return Err(error);
+            }
+            return Ok(ProtocolEvent::Declined {
+                transfer_id: self.transfer_id,
+                name: header.name,
+            });
+        }
+
+        let total = header.known_size().unwrap_or(0);
+        let progress = ProgressReporter::new(&self, offset, total);
+        let mut contents =
+            ContentsReader::new(&mut reader, info, header.size - offset, cancellation);
+        let result = if !matches!(self.sink, ReceiveSink::Disk) {
+            read_to_sink(&mut contents, header, info, &self.sink, progress).await
+        } else if entries.is_empty() {
+            read_socket(
+                &mut contents,
+                header,
+                info,
+                &self.target_path,
+                parts,
+                offset,
+                progress,
+            )
+            .await
+        } else {
+            read_directory(
+                &mut contents,
+                header,
+                entries,
+                &self.target_path,
+                parts,
+                progress,
+            )
+            .await
+        };
+        // Tells the sender whether the contents were kept, it reports `Sent` only then.
+        let status = if result.is_ok() {
+            STATUS_KEPT
+        } else {
+            STATUS_FAILED
+        };
+        let socket = reader.get_mut();
+        let write = async {
+            if info == PROTOCOL_V2 {
+                socket.write_all(&[status]).await?;
+            }
+            socket.close().await
+        };
+        // The contents are kept whether or not the sender hears about it.
+        if let Err(error) = timed(HEADER_TIMEOUT, "confirming the contents", write.err_into()).await
+        {
+            warn!("Could not tell the sender: {}", error);
+        }
+        let mut event = result?;
+
+        event.transfer_id = self.transfer_id;
+        debug!("Finished {:?} ms", start.elapsed().as_millis());
+        Ok(event.into())
+    }
+}
+
 impl<TSocket> InboundUpgrade<TSocket> for TransferPayload
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = TransferPayload;
-    type Error = asyncio::Error;
+    type Output = ProtocolEvent;
+    type Error = TransferError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
 
-    fn upgrade_inbound(self, socket: TSocket, _: Self::Info) -> Self::Future {
-        Box::pin(async move {
-            println!("Upgrade inbound");
-            let start = now();
-            let event = read_socket(socket).await?;
+    fn upgrade_inbound(mut self, socket: TSocket, info: Self::Info) -> Self::Future {
+        if let Some(ids) = &self.transfer_ids {
+            self.transfer_id = ids.fetch_add(1, Ordering::Relaxed);
+        }
+        let transfer_id = self.transfer_id;
+        let span = transfer_span(transfer_id, self.peer.as_ref());
+        async move {
+            debug!("Upgrade inbound {}", info);
+            // libp2p drops the errors of inbound upgrades, so they are reported as events.
+            let mut reader = asyncio::BufReader::new(socket);
+            let read = read_header(&mut reader, info).err_into();
+            let header = match timed(HEADER_TIMEOUT, "waiting for the header", read).await {
+                Ok(header) => header,
+                Err(error) => {
+                    return Ok(ProtocolEvent::Failed {
+                        transfer_id,
+                        name: None,
+                        broadcast: None,
+                        error,
+                    })
+                }
+            };
+            let name = header.name.clone();
+            match self.receive(reader, header, info).await {
+                Ok(event) => Ok(event),
+                Err(error) => {
+                    warn!("Receiving {:?} failed: {}", name, error);
+                    Ok(ProtocolEvent::Failed {
+                        transfer_id,
+                        name: Some(name),
+                        broadcast: None,
+                        error,
+                    })
+                }
+            }
+        }
+        .instrument(span)
+        .boxed()
+    }
+}
 
-            println!("Finished {:?} ms", start.elapsed().as_millis());
-            Ok(event)
-        })
+/// What a transfer is about, attached to everything it logs so interleaved transfers
+/// can be told apart.
+fn transfer_span(transfer_id: u64, peer: Option<&PeerId>) -> Span {
+    let span = info_span!("transfer", id = transfer_id, peer = field::Empty);
+    if let Some(peer) = peer {
+        span.record("peer", field::display(peer.to_base58()));
     }
+    span
 }
 
 impl<TSocket> OutboundUpgrade<TSocket> for TransferPayload
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = ();
-    type Error = asyncio::Error;
+    type Output = ProtocolEvent;
+    type Error = TransferError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
 
-    fn upgrade_outbound(self, mut socket: TSocket, _: Self::Info) -> Self::Future {
-        Box::pin(async move {
-            println!("Upgrade outbound");
-            let start = now();
+    fn upgrade_outbound(self, socket: TSocket, info: Self::Info) -> Self::Future {
+        let span = transfer_span(self.transfer_id, self.peer.as_ref());
+        self.send(socket, info).instrument(span).boxed()
+    }
+}
 
-            println!("Name: {:?}, Path: {:?}", self.name, self.path);
+/// Declines an offer the application is never asked about, so a 2.0 sender sees it
+/// refused rather than the substream dropped. Failing to tell it changes nothing here.
+async fn refuse_offer(socket: &mut (impl AsyncWrite + Unpin), protocol: &str) {
+    let write = async {
+        if protocol == PROTOCOL_V2 {
+            socket.write_all(&[ANSWER_REJECT]).await?;
+            socket.flush().await?;
+        }
+        socket.close().await
+    };
+    if let Err(error) = timed(HEADER_TIMEOUT, "declining the offer", write.err_into()).await {
+        debug!("Could not decline the offer: {}", error);
+    }
+}
 
-            let file = AsyncFile::open(self.path).await.expect("File missing");
-            let mut buff = asyncio::BufReader::new(&file);
-            let mut contents = vec![];
-            buff.read_to_end(&mut contents)
-                .await
-                .expect("Cannot read file");
+/// Reads the receiver's answer to the header: the offset to send the contents from,
+/// `None` when it declined.
+async fn read_answer(socket: &mut (impl AsyncRead + Unpin)) -> Result<Option<u64>, TransferError> {
+    let mut answer = [0u8; 1];
+    socket.read_exact(&mut answer).await?;
+    if answer[0] != ANSWER_ACCEPT {
+        return Ok(None);
+    }
+    let mut offset = [0u8; 8];
+    socket.read_exact(&mut offset).await?;
+    Ok(Some(u64::from_be_bytes(offset)))
+}
 
-            let hash = hash_contents(&contents);
-            let name = add_row(&self.name);
-            let checksum = add_row(&hash);
+impl TransferPayload {
+    async fn send<TSocket>(
+        self,
+        mut socket: TSocket,
+        info: &str,
+    ) -> Result<ProtocolEvent, TransferError>
+    where
+        TSocket: AsyncRead + AsyncWrite + Unpin,
+    {
+        debug!("Upgrade outbound {}", info);
+        let start = now();
+        let mut cancellation = Cancellation::register(self.running.clone(), self.transfer_id);
 
-            socket.write(&name).await.expect("Writing name failed");
-            socket.write(&checksum).await?;
-            socket.write_all(&contents).await.expect("Writing failed");
-            socket.close().await.expect("Failed to close socket");
+        debug!("Name: {:?}, Path: {:?}", self.name, self.path);
 
-            println!("Finished {:?} ms", start.elapsed().as_millis());
-            Ok(())
+        let algorithm = self
+            .hash_algorithm
+            .parse::<HashAlgorithm>()
+            .map_err(|e| TransferError::Protocol(e.to_string()))?;
+
+        let source = self.source.clone().ok_or_else(|| {
+            TransferError::Protocol(format!("Nothing to send for {:?}", self.name))
+        })?;
+        let prepared = cancellation.run(prepare_source(source)).await;
+        let (mut contents, entries, size) = prepared?;
+
+        let header = TransferHeader {
+            name: self.name.to_string(),
+            size,
+            hash_algorithm: algorithm.to_string(),
+            // Follows the contents, see `PROTOCOL_V2`.
+            hash: String::new(),
+            metadata: None,
+            entries: entries.clone(),
+        };
+        let total = header.known_size().unwrap_or(0);
+        let mut hasher = algorithm.hasher();
+        hash_manifest(&entries, &mut hasher);
+        let legacy = info == PROTOCOL_V1;
+        let header = if legacy {
+            if !entries.is_empty() || self.name.as_str().contains('\n') {
+                return Err(TransferError::Protocol(format!(
+                    "{:?} cannot be sent over 1.0",
+                    self.name
+                )));
+            }
+            let hash = cancellation.run(legacy_hash(&mut contents)).await?;
+            format!("{}\n{}\n", self.name.as_str(), hash).into_bytes()
+        } else {
+            header.to_bytes()?
+        };
+        let write = async {
+            socket.write_all(&header).await?;
+            socket.flush().await
+        };
+        timed(HEADER_TIMEOUT, "sending the header", write.err_into()).await?;
+
+        // The receiver takes up to `OFFER_TIMEOUT` to decide, then answers. 1.0 has no
+        // answer, the contents follow the header right away.
+        let answer = if legacy {
+            Ok(Some(0))
+        } else {
+            let what = "waiting for the receiver to answer";
+            let read = timed(
+                OFFER_TIMEOUT + 2 * HEADER_TIMEOUT,
+                what,
+                read_answer(&mut socket),
+            );
+            cancellation.run(read).await
+        };
+        let mut writer = ContentsWriter {
+            socket: &mut socket,
+            cancellation,
+            progress: ProgressReporter::new(&self, 0, total),
+            hasher,
+            framed: !legacy,
+        };
+        let offset = match answer {
+            Ok(Some(offset)) => offset,
+            Ok(None) => {
+                info!("{:?} was declined", self.name);
+                writer.socket.close().await?;
+                return Err(TransferError::Rejected);
+            }
+            // The receiver may still accept, it then finds the abort instead of contents.
+            Err(TransferError::Cancelled) => return writer.abort().await,
+            Err(e) => return Err(e),
+        };
+        // Contents of unknown size and directories start over.
+        let resumable = size != UNKNOWN_SIZE && entries.is_empty();
+        if offset > size || (offset > 0 && !resumable) {
+            return Err(TransferError::Protocol(format!(
+                "Cannot resume {:?} from {} bytes",
+                self.name, offset
+            )));
+        }
+        if offset > 0 {
+            info!("Resuming {:?} from {} bytes", self.name, offset);
+        }
+
+        writer.progress = ProgressReporter::new(&self, offset, total);
+        let len = size - offset;
+        match &mut contents {
+            Outgoing::Path(root) if entries.is_empty() => {
+                writer.write_file(root, offset, len).await?;
+            }
+            Outgoing::Path(root) => {
+                for entry in &entries {
+                    writer
+                        .write_file(&root.join(&entry.path), 0, entry.size)
+                        .await?;
+                }
+            }
+            Outgoing::Bytes(bytes) => {
+                let (kept, rest) = bytes.split_at(offset as usize);
+                writer.skip(kept, offset).await?;
+                writer.write_contents(rest, len).await?;
+            }
+            Outgoing::Reader(reader) => {
+                writer.skip(&mut *reader, offset).await?;
+                let sent = writer.write_contents(reader, len).await?;
+                if sent != len && size != UNKNOWN_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("{:?} ended after {} of {} bytes", self.name, sent, len),
+                    )
+                    .into());
+                }
+            }
+        }
+        writer.finish().await?;
+        // The receiver answers once the contents are verified and stored. A 1.0 one only
+        // drops the substream, waiting for that keeps the connection up meanwhile.
+        let mut status = [0u8; 1];
+        let what = "waiting for the receiver to keep the contents";
+        if legacy {
+            let read = socket.read(&mut status).err_into();
+            let _ = timed(IDLE_TIMEOUT, what, read).await;
+        } else {
+            let read = async {
+                socket
+                    .read_exact(&mut status)
+                    .await
+                    .map_err(|e| match e.kind() {
+                        io::ErrorKind::UnexpectedEof => io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "The receiver closed the stream without confirming the contents",
+                        ),
+                        _ => e,
+                    })
+            };
+            timed(IDLE_TIMEOUT, what, read.err_into()).await?;
+            if status[0] != STATUS_KEPT {
+                info!("{:?} was not kept by the receiver", self.name);
+                return Err(TransferError::Undelivered);
+            }
+        }
+
+        debug!("Finished {:?} ms", start.elapsed().as_millis());
+        Ok(ProtocolEvent::Sent {
+            transfer_id: self.transfer_id,
+            name: self.name.into_string(),
+            broadcast: self.broadcast,
         })
     }
 }
 
-impl From<()> for ProtocolEvent {
-    fn from(_: ()) -> Self {
-        ProtocolEvent::Sent
+impl ProtocolEvent {
+    /// Id of the transfer this event ends, in whichever way it ended.
+    pub fn finished_transfer(&self) -> Option<u64> {
+        match self {
+            ProtocolEvent::Received { transfer_id, .. }
+            | ProtocolEvent::InvalidName { transfer_id, .. }
+            | ProtocolEvent::Declined { transfer_id, .. }
+            | ProtocolEvent::Sent { transfer_id, .. }
+            | ProtocolEvent::Failed { transfer_id, .. }
+            | ProtocolEvent::Cancelled { transfer_id, .. } => Some(*transfer_id),
+            _ => None,
+        }
     }
 }
 
 impl From<TransferPayload> for ProtocolEvent {
     fn from(transfer: TransferPayload) -> Self {
         ProtocolEvent::Received {
+            transfer_id: transfer.transfer_id,
             name: transfer.name,
             path: transfer.path,
             hash: transfer.hash,
+            hash_algorithm: transfer.hash_algorithm,
+            verified: transfer.verified,
             size_bytes: transfer.size_bytes,
+            contents: transfer.contents,
+        }
+    }
+}
+
+impl From<(TransferPayload, ProtocolsHandlerUpgrErr<TransferError>)> for ProtocolEvent {
+    fn from((transfer, error): (TransferPayload, ProtocolsHandlerUpgrErr<TransferError>)) -> Self {
+        match error.into() {
+            TransferError::Cancelled => ProtocolEvent::Cancelled {
+                transfer_id: transfer.transfer_id,
+                name: transfer.name.into_string(),
+                broadcast: transfer.broadcast,
+            },
+            error => ProtocolEvent::Failed {
+                transfer_id: transfer.transfer_id,
+                name: Some(transfer.name.into_string()),
+                broadcast: transfer.broadcast,
+                error,
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+    use std::task::{Context, Poll};
+    use std::{env, process};
+
+    fn header(contents: &[u8]) -> TransferHeader {
+        TransferHeader {
+            name: "notes.txt".to_string(),
+            size: contents.len() as u64,
+            hash_algorithm: HashAlgorithm::default().to_string(),
+            ..TransferHeader::default()
+        }
+    }
+
+    fn frames(contents: &[u8]) -> Vec<u8> {
+        contents
+            .chunks(CHUNK_SIZE)
+            .flat_map(|chunk| [&(chunk.len() as u32).to_be_bytes()[..], chunk].concat())
+            .collect()
+    }
+
+    fn part_paths(dir: &Path, peer: &PeerId, header: &TransferHeader) -> (PathBuf, PathBuf) {
+        let parts = Parts::claim(&dir.to_string_lossy(), Some(peer), header, PROTOCOL_V2, 0);
+        (parts.part_path.clone(), parts.state_path.clone())
+    }
+
+    /// The end of the frames, with the hash of `contents` in the trailer.
+    fn end(contents: &[u8]) -> Vec<u8> {
+        let mut hasher = HashAlgorithm::default().hasher();
+        hasher.update(contents);
+        let hash = hasher.finish();
+        [
+            &FRAME_END.to_be_bytes()[..],
+            &(hash.len() as u16).to_be_bytes(),
+            hash.as_bytes(),
+        ]
+        .concat()
+    }
+
+    /// Offers `header` from `peer` to a receiver writing to `dir`, then sends what
+    /// `contents` returns for the offset it answered and closes the connection. Returns
+    /// the offset and what the receiver reported.
+    async fn offer(
+        dir: &Path,
+        peer: &PeerId,
+        header: &TransferHeader,
+        contents: impl FnOnce(u64) -> Vec<u8>,
+    ) -> (u64, ProtocolEvent) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let receiver = TransferPayload {
+            target_path: dir.to_string_lossy().to_string(),
+            peer: Some(peer.clone()),
+            ..TransferPayload::default()
+        };
+
+        let send = async {
+            client.write_all(&header.to_bytes().unwrap()).await.unwrap();
+            let offset = read_answer(&mut client).await.unwrap().unwrap();
+            client.write_all(&contents(offset)).await.unwrap();
+            client.close().await.unwrap();
+            offset
+        };
+        let (received, offset) =
+            future::join(receiver.upgrade_inbound(server, PROTOCOL_V2), send).await;
+        (offset, received.unwrap())
+    }
+
+    #[test]
+    fn test_interrupted_transfer_resumes() {
+        let dir = env::temp_dir().join(format!("p2pshare-resume-{}", process::id()));
+        let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let peer = PeerId::random();
+        let header = header(&contents);
+        let (part_path, state_path) = part_paths(&dir, &peer, &header);
+
+        // The connection drops halfway: a failure, and what arrived is kept.
+        let (offset, event) =
+            task::block_on(offer(&dir, &peer, &header, |_| frames(&contents[..6000])));
+        assert_eq!(offset, 0);
+        match event {
+            ProtocolEvent::Failed {
+                error: TransferError::Io(e),
+                ..
+            } => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            event => panic!("Expected an interrupted transfer, got {:?}", event),
+        }
+        assert_eq!(fs::read(&part_path).unwrap(), &contents[..6000]);
+
+        // The next offer of the same file continues where the first one stopped.
+        let (offset, event) = task::block_on(offer(&dir, &peer, &header, |offset| {
+            let rest = frames(&contents[offset as usize..]);
+            [rest, end(&contents)].concat()
+        }));
+        assert_eq!(offset, 6000);
+        match event {
+            ProtocolEvent::Received { path, verified, .. } => {
+                assert_eq!(fs::read(path.as_str()).unwrap(), contents);
+                assert!(verified);
+            }
+            event => panic!("Expected a Received event, got {:?}", event),
+        }
+        assert!(!part_path.exists());
+        assert!(!state_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resumed_sends_hash_the_whole_contents() {
+        let dir = env::temp_dir().join(format!("p2pshare-resend-{}", process::id()));
+        let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let peer = PeerId::random();
+        task::block_on(offer(&dir, &peer, &header(&contents), |_| {
+            frames(&contents[..6000])
+        }));
+
+        let sender = TransferPayload {
+            name: FileName::from("notes.txt"),
+            hash_algorithm: HashAlgorithm::default().to_string(),
+            source: Some(SendSource::bytes(contents.clone())),
+            ..TransferPayload::default()
+        };
+        let receiver = TransferPayload {
+            target_path: dir.to_string_lossy().to_string(),
+            peer: Some(peer),
+            ..TransferPayload::default()
+        };
+        let (sent, received) = task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            future::join(
+                sender.upgrade_outbound(client, PROTOCOL_V2),
+                receiver.upgrade_inbound(server, PROTOCOL_V2),
+            )
+            .await
+        });
+        assert!(matches!(sent.unwrap(), ProtocolEvent::Sent { .. }));
+        match received.unwrap() {
+            ProtocolEvent::Received { path, verified, .. } => {
+                assert_eq!(fs::read(path.as_str()).unwrap(), contents);
+                assert!(verified);
+            }
+            event => panic!("Expected a Received event, got {:?}", event),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_directory_round_trip() {
+        let root = env::temp_dir().join(format!("p2pshare-dir-{}", process::id()));
+        let source = root.join("photos");
+        let target = root.join("received");
+        let files: [(&str, &[u8]); 3] = [
+            ("a.txt", b"first"),
+            ("nested/b.txt", b"second"),
+            ("nested/deeper/c.txt", b""),
+        ];
+        for (path, contents) in &files {
+            let path = source.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let sender = TransferPayload {
+            name: FileName::from("photos"),
+            hash_algorithm: HashAlgorithm::default().to_string(),
+            source: Some(SendSource::Path(source.to_string_lossy().to_string())),
+            ..TransferPayload::default()
+        };
+        let receiver = TransferPayload {
+            target_path: target.to_string_lossy().to_string(),
+            peer: Some(PeerId::random()),
+            ..TransferPayload::default()
+        };
+        let (sent, received) = task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            future::join(
+                sender.upgrade_outbound(client, PROTOCOL_V2),
+                receiver.upgrade_inbound(server, PROTOCOL_V2),
+            )
+            .await
+        });
+        assert!(matches!(sent.unwrap(), ProtocolEvent::Sent { .. }));
+        let received = match received.unwrap() {
+            ProtocolEvent::Received {
+                name,
+                path,
+                hash,
+                hash_algorithm,
+                size_bytes,
+                verified,
+                ..
+            } => {
+                assert!(verified);
+                TransferPayload {
+                    hash_algorithm,
+                    ..TransferPayload::new(name, path, hash, size_bytes)
+                }
+            }
+            event => panic!("Expected a Received event, got {:?}", event),
+        };
+        for (path, contents) in &files {
+            let path = Path::new(received.path.as_str()).join(path);
+            assert_eq!(fs::read(path).unwrap(), *contents);
+        }
+        received.check_file().unwrap();
+
+        // The same contents under other names hash differently.
+        let mut hasher = HashAlgorithm::default().hasher();
+        for (_, contents) in &files {
+            hasher.update(contents);
+        }
+        assert_ne!(received.hash.as_str(), hasher.finish());
+        fs::rename(
+            Path::new(received.path.as_str()).join("a.txt"),
+            Path::new(received.path.as_str()).join("z.txt"),
+        )
+        .unwrap();
+        assert!(matches!(
+            received.check_file(),
+            Err(TransferError::HashMismatch { .. })
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_single_files_fall_back_to_legacy_peers() {
+        let contents = b"contents for an old peer".to_vec();
+        let payload = |source| TransferPayload {
+            name: FileName::from("notes.txt"),
+            hash_algorithm: HashAlgorithm::default().to_string(),
+            source: Some(source),
+            ..TransferPayload::default()
+        };
+        let offered = |source| payload(source).protocol_info().collect::<Vec<_>>();
+        assert_eq!(
+            offered(SendSource::bytes(contents.clone())),
+            vec![PROTOCOL_V2, PROTOCOL_V1]
+        );
+        assert_eq!(
+            offered(SendSource::Path("Cargo.toml".to_string())),
+            vec![PROTOCOL_V2, PROTOCOL_V1]
+        );
+        assert_eq!(
+            offered(SendSource::Path("src".to_string())),
+            vec![PROTOCOL_V2]
+        );
+        assert_eq!(
+            offered(SendSource::reader(&b"streamed"[..], Some(8))),
+            vec![PROTOCOL_V2]
+        );
+
+        let sender = payload(SendSource::bytes(contents.clone()));
+        let receiver = TransferPayload {
+            sink: ReceiveSink::memory(1 << 20),
+            ..TransferPayload::default()
+        };
+        let (sent, received) = task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            future::join(
+                sender.upgrade_outbound(client, PROTOCOL_V1),
+                receiver.upgrade_inbound(server, PROTOCOL_V1),
+            )
+            .await
+        });
+        assert!(matches!(sent.unwrap(), ProtocolEvent::Sent { .. }));
+        match received.unwrap() {
+            ProtocolEvent::Received {
+                hash,
+                hash_algorithm,
+                contents: received,
+                ..
+            } => {
+                let sha1 = "f23308beacb49ad579a68e84d4560bffd1ecf54d";
+                assert_eq!(hash, ContentHash::from(sha1));
+                assert_eq!(hash_algorithm, LEGACY_HASH_ALGORITHM);
+                assert_eq!(received.as_deref(), Some(&contents));
+            }
+            event => panic!("Expected a Received event, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_legacy_header_lines_are_capped() {
+        let read =
+            |bytes: Vec<u8>| task::block_on(read_header_v1(&mut futures::io::Cursor::new(bytes)));
+        let header = read(b"notes.txt\nabc123\ncontents".to_vec()).unwrap();
+        assert_eq!(header.name, "notes.txt");
+        assert_eq!(header.hash, "abc123");
+
+        let endless = vec![b'a'; MAX_LEGACY_LINE_LEN as usize + 10];
+        let error = read(endless).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_invalid_names_are_refused_on_both_sides() {
+        let sender = TransferPayload {
+            name: FileName::from(".."),
+            hash_algorithm: HashAlgorithm::default().to_string(),
+            source: Some(SendSource::bytes(b"escape".to_vec())),
+            ..TransferPayload::default()
+        };
+        let receiver = TransferPayload {
+            sink: ReceiveSink::memory(1 << 20),
+            ..TransferPayload::default()
+        };
+        let (sent, received) = task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            future::join(
+                sender.upgrade_outbound(client, PROTOCOL_V2),
+                receiver.upgrade_inbound(server, PROTOCOL_V2),
+            )
+            .await
+        });
+        assert!(matches!(sent, Err(TransferError::Rejected)));
+        match received.unwrap() {
+            ProtocolEvent::InvalidName { name, .. } => assert_eq!(name, ".."),
+            event => panic!("Expected an InvalidName event, got {:?}", event),
+        }
+    }
+
+    /// Takes everything written to it, then fails to close like a full disk would.
+    struct FailingClose;
+
+    impl AsyncWrite for FailingClose {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::Error::other("disk full")))
+        }
+    }
+
+    #[test]
+    fn test_contents_the_receiver_cannot_keep_fail_the_sender() {
+        let transfer = |source: SendSource, sink: ReceiveSink| {
+            let sender = TransferPayload {
+                name: FileName::from("notes.txt"),
+                hash_algorithm: HashAlgorithm::default().to_string(),
+                source: Some(source),
+                ..TransferPayload::default()
+            };
+            let receiver = TransferPayload {
+                sink,
+                ..TransferPayload::default()
+            };
+            task::block_on(async {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let client = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let (server, _) = listener.accept().await.unwrap();
+                let (sent, received) = future::join(
+                    sender.upgrade_outbound(client, PROTOCOL_V2),
+                    receiver.upgrade_inbound(server, PROTOCOL_V2),
+                )
+                .await;
+                (sent, received.unwrap())
+            })
+        };
+
+        // Everything arrived, storing it failed.
+        let contents = vec![7u8; 10_000];
+        let sink = ReceiveSink::writer(|_| Ok(FailingClose));
+        let (sent, received) = transfer(SendSource::bytes(contents), sink);
+        assert!(matches!(sent, Err(TransferError::Undelivered)));
+        assert!(matches!(received, ProtocolEvent::Failed { .. }));
+
+        // More arrived than the receiver can hold.
+        let contents = vec![7u8; 100_000];
+        let source = SendSource::reader(futures::io::Cursor::new(contents), None);
+        let (sent, received) = transfer(source, ReceiveSink::memory(4096));
+        assert!(sent.is_err());
+        assert!(matches!(received, ProtocolEvent::Failed { .. }));
+    }
+
+    #[test]
+    fn test_contents_beyond_the_announced_size_are_refused() {
+        let dir = env::temp_dir().join(format!("p2pshare-too-long-{}", process::id()));
+        let contents = vec![7u8; 10_000];
+        let peer = PeerId::random();
+        let header = header(&contents[..9000]);
+
+        let (_, event) = task::block_on(offer(&dir, &peer, &header, |_| {
+            [frames(&contents), end(&contents)].concat()
+        }));
+        assert!(matches!(
+            event,
+            ProtocolEvent::Failed {
+                error: TransferError::Protocol(_),
+                ..
+            }
+        ));
+        let (part_path, state_path) = part_paths(&dir, &peer, &header);
+        assert!(!part_path.exists());
+        assert!(!state_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_sink_is_capped() {
+        let contents = vec![7u8; 10_000];
+        let receive = |header: TransferHeader| {
+            task::block_on(async {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let (server, _) = listener.accept().await.unwrap();
+                let receiver = TransferPayload {
+                    sink: ReceiveSink::memory(4096),
+                    ..TransferPayload::default()
+                };
+                let send = async {
+                    client.write_all(&header.to_bytes().unwrap()).await.unwrap();
+                    let answer = read_answer(&mut client).await.unwrap();
+                    if answer.is_some() {
+                        let sent = [frames(&contents), end(&contents)].concat();
+                        // The receiver may give up before everything is written.
+                        let _ = client.write_all(&sent).await;
+                    }
+                    client.close().await.ok();
+                    answer
+                };
+                let (received, answer) =
+                    future::join(receiver.upgrade_inbound(server, PROTOCOL_V2), send).await;
+                (answer, received.unwrap())
+            })
+        };
+
+        // Announced contents that do not fit are declined.
+        let (answer, event) = receive(header(&contents));
+        assert_eq!(answer, None);
+        assert!(matches!(event, ProtocolEvent::Declined { .. }));
+
+        // Unannounced ones fail once they exceed the limit.
+        let unknown = TransferHeader {
+            size: UNKNOWN_SIZE,
+            ..header(&contents)
+        };
+        let (answer, event) = receive(unknown);
+        assert_eq!(answer, Some(0));
+        match event {
+            ProtocolEvent::Failed {
+                error: TransferError::Io(error),
+                ..
+            } => assert_eq!(error.kind(), io::ErrorKind::InvalidData),
+            event => panic!("Expected the limit to be exceeded, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_aborted_transfer_removes_part() {
+        let dir = env::temp_dir().join(format!("p2pshare-abort-{}", process::id()));
+        let contents = vec![7u8; 10_000];
+        let peer = PeerId::random();
+        let header = header(&contents);
+
+        let (_, event) = task::block_on(offer(&dir, &peer, &header, |_| {
+            let sent = frames(&contents[..6000]);
+            [sent, FRAME_ABORT.to_be_bytes().to_vec()].concat()
+        }));
+        assert!(matches!(event, ProtocolEvent::Cancelled { .. }));
+        let (part_path, state_path) = part_paths(&dir, &peer, &header);
+        assert!(!part_path.exists());
+        assert!(!state_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parts_are_kept_per_sender() {
+        let dir = env::temp_dir().join(format!("p2pshare-senders-{}", process::id()));
+        let contents = vec![7u8; 10_000];
+        let header = header(&contents);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+
+        task::block_on(offer(&dir, &alice, &header, |_| frames(&contents[..6000])));
+        let (offset, _) = task::block_on(offer(&dir, &bob, &header, |_| vec![]));
+        assert_eq!(offset, 0);
+        let (offset, _) = task::block_on(offer(&dir, &alice, &header, |_| vec![]));
+        assert_eq!(offset, 6000);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resumed_contents_are_verified() {
+        let dir = env::temp_dir().join(format!("p2pshare-mismatch-{}", process::id()));
+        let contents = vec![7u8; 10_000];
+        let peer = PeerId::random();
+        let header = header(&contents);
+
+        // What is kept is not what the sender has, which only the hash can tell.
+        task::block_on(offer(&dir, &peer, &header, |_| frames(&[8u8; 6000])));
+        let (offset, event) = task::block_on(offer(&dir, &peer, &header, |offset| {
+            let rest = frames(&contents[offset as usize..]);
+            [rest, end(&contents)].concat()
+        }));
+        assert_eq!(offset, 6000);
+        assert!(matches!(
+            event,
+            ProtocolEvent::Failed {
+                error: TransferError::HashMismatch { .. },
+                ..
+            }
+        ));
+        let (part_path, state_path) = part_paths(&dir, &peer, &header);
+        assert!(!part_path.exists());
+        assert!(!state_path.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stalled_steps_time_out() {
+        let stalled = future::pending::<Result<(), TransferError>>();
+        let result = task::block_on(timed(Duration::from_millis(10), "testing", stalled));
+        match result {
+            Err(TransferError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            result => panic!("Expected a timeout, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parts_in_use_are_not_shared() {
+        let header = header(b"contents");
+        let peer = PeerId::random();
+        let first = Parts::claim("/tmp/files", Some(&peer), &header, PROTOCOL_V2, 1);
+        let second = Parts::claim("/tmp/files", Some(&peer), &header, PROTOCOL_V2, 2);
+        assert!(first.resumable);
+        assert!(!second.resumable);
+        assert_ne!(first.part_path, second.part_path);
+
+        drop((first, second));
+        assert!(Parts::claim("/tmp/files", Some(&peer), &header, PROTOCOL_V2, 3).resumable);
+    }
+
+    /// Reads `len` bytes, then fails like a dropped connection.
+    struct Dropped(u64);
+
+    impl AsyncRead for Dropped {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.0 == 0 {
+                let error = io::Error::new(io::ErrorKind::ConnectionReset, "dropped");
+                return Poll::Ready(Err(error));
+            }
+            let n = buf.len().min(self.0 as usize);
+            buf[..n].fill(7);
+            self.0 -= n as u64;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn test_interrupted_legacy_transfer_removes_part() {
+        let dir = env::temp_dir().join(format!("p2pshare-legacy-{}", process::id()));
+        let target_path = dir.to_string_lossy().to_string();
+        // What read_header_v1 makes of a 1.0 header.
+        let header = TransferHeader {
+            name: "notes.txt".to_string(),
+            hash_algorithm: LEGACY_HASH_ALGORITHM.to_string(),
+            hash: "f23308beacb49ad579a68e84d4560bffd1ecf54d".to_string(),
+            ..TransferHeader::default()
+        };
+        let peer = PeerId::random();
+        let parts = Parts::claim(&target_path, Some(&peer), &header, PROTOCOL_V1, 0);
+        assert!(!parts.resumable);
+
+        let receiver = TransferPayload::default();
+        let mut cancellation = Cancellation::register(None, 0);
+        let mut reader = Dropped(6000);
+        let mut contents = ContentsReader::new(&mut reader, PROTOCOL_V1, 0, &mut cancellation);
+        let progress = ProgressReporter::new(&receiver, 0, 0);
+        let read = read_socket(
+            &mut contents,
+            header,
+            PROTOCOL_V1,
+            &target_path,
+            &parts,
+            0,
+            progress,
+        );
+        assert!(matches!(task::block_on(read), Err(TransferError::Io(_))));
+        assert!(!parts.part_path.exists());
+        assert!(!parts.state_path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use std::path::PathBuf;
+use std::{error, fmt};
+
+/// Device names Windows refuses to create, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NameError {
+    Empty,
+    ParentDirectory,
+    Reserved(String),
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "file name is empty"),
+            NameError::ParentDirectory => write!(f, "file name refers to a parent directory"),
+            NameError::Reserved(name) => write!(f, "file name {:?} is reserved", name),
+            NameError::InvalidCharacter(c) => write!(f, "file name contains {:?}", c),
+        }
+    }
+}
+
+impl error::Error for NameError {}
+
+/// Turns a name sent by a remote peer into a single file name that is safe to join
+/// with the download directory.
+///
+/// Leading directories are stripped, so `docs/notes.txt` becomes `notes.txt`, but any
+/// `..` component rejects the whole name.
+pub fn sanitize_name(name: &str) -> Result<String, NameError> {
+    let components: Vec<&str> = name.split(is_separator).collect();
+    if components.contains(&"..") {
+        return Err(NameError::ParentDirectory);
+    }
+    let name = components
+        .into_iter()
+        .rev()
+        .find(|c| !c.is_empty() && *c != ".")
+        .ok_or(NameError::Empty)?;
+    sanitize_component(name)
+}
+
+/// Turns a `/` separated path of a directory entry into a relative path, validating
+/// every component. Nothing is stripped, so the layout of the directory is kept.
+pub fn sanitize_relative_path(path: &str) -> Result<PathBuf, NameError> {
+    path.split('/').map(sanitize_component).collect()
+}
+
+/// Validates a single path component, without stripping anything.
+pub fn sanitize_component(component: &str) -> Result<String, NameError> {
+    match component {
+        "" | "." => return Err(NameError::Empty),
+        ".." => return Err(NameError::ParentDirectory),
+        _ => {}
+    }
+    if let Some(c) = component
+        .chars()
+        .find(|c| is_separator(*c) || c.is_control())
+    {
+        return Err(NameError::InvalidCharacter(c));
+    }
+
+    let stem = component.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        return Err(NameError::Reserved(component.to_string()));
+    }
+    Ok(component.to_string())
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || c == '\\'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_name_is_kept() {
+        assert_eq!(sanitize_name("report.pdf"), Ok("report.pdf".to_string()));
+        assert_eq!(sanitize_name(".bashrc"), Ok(".bashrc".to_string()));
+    }
+
+    #[test]
+    fn test_directories_are_stripped() {
+        assert_eq!(sanitize_name("docs/notes.txt"), Ok("notes.txt".to_string()));
+        assert_eq!(sanitize_name("/etc/passwd"), Ok("passwd".to_string()));
+        assert_eq!(
+            sanitize_name("C:\\Users\\me\\a.txt"),
+            Ok("a.txt".to_string())
+        );
+        assert_eq!(sanitize_name("./a.txt/"), Ok("a.txt".to_string()));
+    }
+
+    #[test]
+    fn test_parent_directory_is_rejected() {
+        assert_eq!(
+            sanitize_name("../../home/user/.bashrc"),
+            Err(NameError::ParentDirectory)
+        );
+        assert_eq!(sanitize_name(".."), Err(NameError::ParentDirectory));
+        assert_eq!(sanitize_name("a/../b"), Err(NameError::ParentDirectory));
+        assert_eq!(sanitize_name("..\\secret"), Err(NameError::ParentDirectory));
+    }
+
+    #[test]
+    fn test_empty_name_is_rejected() {
+        assert_eq!(sanitize_name(""), Err(NameError::Empty));
+        assert_eq!(sanitize_name("/"), Err(NameError::Empty));
+        assert_eq!(sanitize_name("./."), Err(NameError::Empty));
+    }
+
+    #[test]
+    fn test_reserved_name_is_rejected() {
+        assert_eq!(
+            sanitize_name("con"),
+            Err(NameError::Reserved("con".to_string()))
+        );
+        assert_eq!(
+            sanitize_name("NUL.txt"),
+            Err(NameError::Reserved("NUL.txt".to_string()))
+        );
+        assert_eq!(sanitize_name("console.log"), Ok("console.log".to_string()));
+    }
+
+    #[test]
+    fn test_control_characters_are_rejected() {
+        assert_eq!(
+            sanitize_name("evil\nname"),
+            Err(NameError::InvalidCharacter('\n'))
+        );
+        assert_eq!(
+            sanitize_name("nul\0byte"),
+            Err(NameError::InvalidCharacter('\0'))
+        );
+    }
+
+    #[test]
+    fn test_component_keeps_separators_out() {
+        assert_eq!(
+            sanitize_component("a/b"),
+            Err(NameError::InvalidCharacter('/'))
+        );
+        assert_eq!(sanitize_component("."), Err(NameError::Empty));
+    }
+
+    #[test]
+    fn test_relative_path_keeps_layout() {
+        assert_eq!(
+            sanitize_relative_path("src/bin/main.rs"),
+            Ok(PathBuf::from("src").join("bin").join("main.rs"))
+        );
+        assert_eq!(
+            sanitize_relative_path("src/../../etc/passwd"),
+            Err(NameError::ParentDirectory)
+        );
+        assert_eq!(sanitize_relative_path("/etc/passwd"), Err(NameError::Empty));
+        assert_eq!(sanitize_relative_path("a//b"), Err(NameError::Empty));
+        assert_eq!(
+            sanitize_relative_path("a\\b"),
+            Err(NameError::InvalidCharacter('\\'))
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use libp2p::core::PeerId;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::{config_dir, write_private};
+
+/// Where the trusted peers are kept unless told otherwise.
+pub fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("trusted"))
+}
+
+/// Peers we know by name. Files from them are accepted without asking, see
+/// `TransferBehaviour::set_trust_store`.
+///
+/// Stored as one `<peer id> <name>` line per peer.
+#[derive(Clone, Debug, Default)]
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    peers: HashMap<PeerId, String>,
+}
+
+impl TrustStore {
+    /// Reads the store at `path`, which does not have to exist yet. Changes are saved
+    /// back to it.
+    pub fn load(path: &Path) -> Result<TrustStore, io::Error> {
+        let mut store = TrustStore {
+            path: Some(path.to_path_buf()),
+            peers: HashMap::new(),
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e),
+        };
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.splitn(2, ' ');
+            let peer = words.next().and_then(|peer| peer.parse::<PeerId>().ok());
+            match (peer, words.next()) {
+                (Some(peer), Some(name)) => {
+                    store.peers.insert(peer, name.trim().to_string());
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid peer on line {} of {:?}", number + 1, path),
+                    ))
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn is_trusted(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    pub fn name(&self, peer: &PeerId) -> Option<&str> {
+        self.peers.get(peer).map(String::as_str)
+    }
+
+    /// Trusted peers and their names, sorted by name.
+    pub fn peers(&self) -> Vec<(&PeerId, &str)> {
+        let mut peers: Vec<(&PeerId, &str)> = self
+            .peers
+            .iter()
+            .map(|(peer, name)| (peer, name.as_str()))
+            .collect();
+        peers.sort_by(|a, b| a.1.cmp(b.1));
+        peers
+    }
+
+    /// Trusts `peer` under `name`, replacing the name it had before.
+    pub fn add(&mut self, peer: PeerId, name: String) -> Result<(), io::Error> {
+        if name.trim().is_empty() || name.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Names must be a single, non-empty line",
+            ));
+        }
+        self.peers.insert(peer, name.trim().to_string());
+        self.save()
+    }
+
+    /// Returns whether the peer was trusted.
+    pub fn remove(&mut self, peer: &PeerId) -> Result<bool, io::Error> {
+        let removed = self.peers.remove(peer).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = String::new();
+        for (peer, name) in self.peers() {
+            contents.push_str(&format!("{} {}\n", peer.to_base58(), name));
+        }
+        write_private(path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::identity::Keypair;
+    use std::{env, process};
+
+    fn peer() -> PeerId {
+        PeerId::from(Keypair::generate_ed25519().public())
+    }
+
+    fn store_path(test: &str) -> PathBuf {
+        env::temp_dir()
+            .join(format!("p2pshare-trust-{}-{}", test, process::id()))
+            .join("trusted")
+    }
+
+    #[test]
+    fn test_add_remove_and_lookup() {
+        let (alice, bob) = (peer(), peer());
+        let mut store = TrustStore::default();
+        store
+            .add(alice.clone(), " Alice Smith ".to_string())
+            .unwrap();
+        store.add(bob.clone(), "bob".to_string()).unwrap();
+
+        assert!(store.is_trusted(&alice));
+        assert_eq!(store.name(&alice), Some("Alice Smith"));
+        assert_eq!(store.peers(), vec![(&alice, "Alice Smith"), (&bob, "bob")]);
+
+        store.add(bob.clone(), "Bob".to_string()).unwrap();
+        assert_eq!(store.name(&bob), Some("Bob"));
+
+        assert!(store.remove(&alice).unwrap());
+        assert!(!store.remove(&alice).unwrap());
+        assert!(!store.is_trusted(&alice));
+        assert_eq!(store.name(&alice), None);
+
+        assert!(store.add(alice.clone(), " ".to_string()).is_err());
+        assert!(store.add(alice.clone(), "a\nb".to_string()).is_err());
+        assert!(!store.is_trusted(&alice));
+    }
+
+    #[test]
+    fn test_changes_are_saved() {
+        let path = store_path("saved");
+        let (alice, bob) = (peer(), peer());
+
+        let mut store = TrustStore::load(&path).unwrap();
+        assert!(store.peers().is_empty());
+        store.add(alice.clone(), "Alice".to_string()).unwrap();
+        store.add(bob.clone(), "Bob".to_string()).unwrap();
+        store.remove(&bob).unwrap();
+
+        let loaded = TrustStore::load(&path).unwrap();
+        assert_eq!(loaded.peers(), vec![(&alice, "Alice")]);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{} Alice\n", alice.to_base58())
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_load_skips_comments_and_rejects_garbage() {
+        let path = store_path("load");
+        let alice = peer();
+        let contents = format!("# trusted peers\n\n{} Alice Smith\n", alice.to_base58());
+        write_private(&path, contents.as_bytes()).unwrap();
+        let store = TrustStore::load(&path).unwrap();
+        assert_eq!(store.name(&alice), Some("Alice Smith"));
+
+        write_private(&path, b"not-a-peer Alice\n").unwrap();
+        let error = TrustStore::load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
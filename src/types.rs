@@ -0,0 +1,61 @@
+use std::fmt;
+use std::path::Path;
+
+/// Declares a `String` wrapper, so the different strings describing a transfer
+/// cannot be passed in place of each other.
+macro_rules! string_newtype {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn into_string(self) -> String {
+                self.0
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(value: String) -> Self {
+                $name(value)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(value: &str) -> Self {
+                $name(value.to_string())
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+string_newtype!(
+    /// Name of a transferred file, as announced by the sender.
+    FileName
+);
+
+string_newtype!(
+    /// Location of a file on the local disk.
+    FilePath
+);
+
+string_newtype!(
+    /// Hex digest of the file contents.
+    ContentHash
+);
+
+impl AsRef<Path> for FilePath {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}